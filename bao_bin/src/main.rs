@@ -1,22 +1,83 @@
 use arrayref::array_ref;
 use failure::{err_msg, Error};
 use serde::Deserialize;
-use std::fs::{File, OpenOptions};
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use zeroize::Zeroizing;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const CHUNK_SIZE: u64 = 1024;
 
 // Note that docopt.rs currently has a bug related to commands wrapped over multiple lines, so
 // don't wrap them. https://github.com/docopt/docopt.rs/issues/244
 const USAGE: &str = "
-Usage: bao hash [<inputs>...]
-       bao encode <input> (<output> | --outboard=<file>)
-       bao decode <hash> [<input>] [<output>] [--outboard=<file>] [--start=<offset>] [--count=<count>]
+Usage: bao hash [<inputs>...] [--files-from=<file>] [--key-file=<file> | --key-env=<var>]
+       bao encode <input> (<output> | --outboard=<file>) [--key-file=<file> | --key-env=<var>]
+       bao decode <hash> [<input>] [<output>] [--outboard=<file>] [--start=<offset>] [--count=<count>] [--resume] [--sparse] [--key-file=<file> | --key-env=<var>]
        bao slice <start> <count> [<input>] [<output>] [--outboard=<file>]
-       bao decode-slice <hash> <start> <count> [<input>] [<output>]
+       bao decode-slice <hash> <start> <count> [<input>] [<output>] [--key-file=<file> | --key-env=<var>]
+       bao verify [--jobs=<n>] <targets>... [--key-file=<file> | --key-env=<var>]
+       bao diff <a> <b> [--encoded | --outboards] [--stat | --json]
+       bao dedup-stats [--depth=<d>] <outboards>...
        bao (--help | --version)
+
+Input lists:
+  --files-from=<file>  Hash the paths listed in a file (or - for stdin), one
+                       per line, or NUL-separated as produced by `find -print0`.
+
+Output files are written to a temporary file and renamed into place when the
+command succeeds. Replacing an existing output keeps its permissions, and a
+symlinked output replaces the file the link points to. The new file is owned
+by the user running bao, and other hard links to the old file still point to
+the old contents.
+
+Encode can also write to stdout or a pipe. It then holds the tree in memory,
+about 1/16 of the input size, and for a combined encoding it reads the input
+twice, so the input must be a file.
+
+If --outboard isn't given to decode or slice, and the input is a file with an
+outboard file next to it named <input>.obao, that outboard is used.
+
+With --resume, decode writes to <output>.bao-partial and periodically records
+how much has been verified in <output>.bao-resume. If it's interrupted, running
+the same command again continues from the last checkpoint.
+
+Verify takes targets of the form <hash>:<path>. Each path is checked as a
+combined encoding, or if <path>.obao exists, as content with that outboard.
+Up to --jobs targets are verified at once, defaulting to the number of CPUs.
+
+Diff prints the byte ranges where two files differ, rounded out to whole chunks.
+Like diff(1), it exits with status 0 if there are none, 1 if there are any, and
+2 if the files couldn't be compared. Each file uses its <file>.obao outboard if
+there is one and its root hash matches the file, and otherwise it's hashed from
+scratch. If one file is longer, the content they have in common is compared,
+and everything past it is reported as differing.
+  --encoded    Compare two combined encodings instead. If their lengths differ,
+               the whole content is reported as differing.
+  --outboards  Compare two outboard files instead. Content of one chunk or less
+               isn't in the outboard, so it's always reported as differing, and
+               so is the whole content if the lengths differ.
+  --stat       Print only the number of ranges and bytes that differ.
+  --json       Print the ranges as a JSON array of {start, end} objects.
+
+Dedup-stats estimates how much space deduplicating a set of outboard files
+would save, by comparing the subtrees --depth levels below each root (default
+8). Only identical content at the same offset counts as shared.
+
+With --sparse, decode skips over blocks of zeros in the output file instead of
+writing them, so that restoring a sparse file doesn't allocate disk space for
+its holes.
+
+Keyed mode:
+  --key-file=<file>  Use the keyed hash, with the 32-byte key read from a file.
+  --key-env=<var>    Use the keyed hash, with the key read from an environment
+                     variable as 64 hex characters.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -26,38 +87,79 @@ struct Args {
     cmd_hash: bool,
     cmd_slice: bool,
     cmd_decode_slice: bool,
+    cmd_dedup_stats: bool,
+    cmd_diff: bool,
+    cmd_verify: bool,
     arg_input: Option<PathBuf>,
     arg_inputs: Vec<PathBuf>,
+    arg_outboards: Vec<PathBuf>,
     arg_output: Option<PathBuf>,
     arg_hash: String,
     arg_start: u64,
+    arg_targets: Vec<String>,
+    arg_a: PathBuf,
+    arg_b: PathBuf,
     arg_count: u64,
     flag_count: Option<u64>,
+    flag_depth: Option<u32>,
+    flag_encoded: bool,
+    flag_files_from: Option<PathBuf>,
     flag_help: bool,
+    flag_jobs: Option<usize>,
+    flag_json: bool,
+    flag_key_env: Option<String>,
+    flag_key_file: Option<PathBuf>,
     flag_outboard: Option<PathBuf>,
+    flag_outboards: bool,
+    flag_resume: bool,
+    flag_sparse: bool,
     flag_start: Option<u64>,
+    flag_stat: bool,
     flag_version: bool,
 }
 
-fn main() -> Result<(), Error> {
+fn main() {
     let args: Args = docopt::Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let result = register_signal_handlers()
+        .map_err(Error::from)
+        .and_then(|_| run(&args));
+    // If we were interrupted, everything has unwound by now, and partial outputs have been
+    // cleaned up. Exit with the conventional status for death by signal.
+    if let Some(signal) = interrupted_by() {
+        eprintln!("bao: interrupted");
+        std::process::exit(128 + signal);
+    }
+    if let Err(e) = result {
+        eprintln!("bao: {}", e);
+        // Like diff(1), bao diff uses status 1 to mean the files differ, so its errors use 2.
+        std::process::exit(if args.cmd_diff { 2 } else { 1 });
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
     if args.flag_help {
         print!("{}", USAGE);
     } else if args.flag_version {
         println!("{}", VERSION);
     } else if args.cmd_hash {
-        hash(&args)?;
+        hash(args)?;
     } else if args.cmd_encode {
-        encode(&args)?;
+        encode(args)?;
     } else if args.cmd_decode {
-        decode(&args)?;
+        decode(args)?;
     } else if args.cmd_slice {
-        slice(&args)?;
+        slice(args)?;
     } else if args.cmd_decode_slice {
-        decode_slice(&args)?;
+        decode_slice(args)?;
+    } else if args.cmd_verify {
+        verify(args)?;
+    } else if args.cmd_diff {
+        diff(args)?;
+    } else if args.cmd_dedup_stats {
+        dedup_stats(args)?;
     } else {
         unreachable!();
     }
@@ -65,6 +167,38 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+// The first SIGINT or SIGTERM sets a flag, which long-running loops check so that they can
+// unwind and clean up. A second signal exits immediately, in case we're blocked somewhere that
+// never checks the flag, like a read from a terminal.
+static SIGNAL: OnceLock<Arc<AtomicUsize>> = OnceLock::new();
+
+fn register_signal_handlers() -> io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let signal = SIGNAL.get_or_init(|| Arc::new(AtomicUsize::new(0)));
+    for &sig in &[SIGINT, SIGTERM] {
+        let already_signaled = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register_conditional_shutdown(sig, 128 + sig, already_signaled.clone())?;
+        signal_hook::flag::register(sig, already_signaled)?;
+        signal_hook::flag::register_usize(sig, signal.clone(), sig as usize)?;
+    }
+    Ok(())
+}
+
+fn interrupted_by() -> Option<i32> {
+    match SIGNAL.get()?.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal as i32),
+    }
+}
+
+fn check_interrupted() -> io::Result<()> {
+    if interrupted_by().is_some() {
+        Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+    } else {
+        Ok(())
+    }
+}
+
 fn copy_reader_to_writer(
     reader: &mut impl io::Read,
     writer: &mut impl io::Write,
@@ -73,7 +207,9 @@ fn copy_reader_to_writer(
     let mut buf = [0; 65536];
     let mut written = 0;
     loop {
-        let len = match reader.read(&mut buf) {
+        let result = reader.read(&mut buf);
+        check_interrupted()?;
+        let len = match result {
             Ok(0) => return Ok(written),
             Ok(len) => len,
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
@@ -84,39 +220,47 @@ fn copy_reader_to_writer(
     }
 }
 
-fn hash_one(maybe_path: &Option<PathBuf>) -> Result<bao::Hash, Error> {
+fn hash_one(maybe_path: &Option<PathBuf>, key: Option<&Key>) -> Result<bao::Hash, Error> {
     let mut input = open_input(maybe_path)?;
+    let mut hasher = match key {
+        Some(key) => blake3::Hasher::new_keyed(key),
+        None => blake3::Hasher::new(),
+    };
     if let Some(map) = maybe_memmap_input(&input)? {
-        let hash;
         #[cfg(feature = "rayon")]
         {
             // multi-threaded
-            hash = blake3::Hasher::new().update_rayon(&map).finalize();
+            hasher.update_rayon(&map);
         }
         #[cfg(not(feature = "rayon"))]
         {
             // single-threaded
-            hash = blake3::hash(&map);
+            hasher.update(&map);
         }
-        Ok(hash)
     } else {
-        let mut hasher = blake3::Hasher::new();
         copy_reader_to_writer(&mut input, &mut hasher)?;
-        Ok(hasher.finalize())
     }
+    Ok(hasher.finalize())
 }
 
 fn hash(args: &Args) -> Result<(), Error> {
-    if !args.arg_inputs.is_empty() {
+    let key = read_key(args)?;
+    let mut inputs = args.arg_inputs.clone();
+    if args.flag_files_from.is_some() {
+        let mut list = Vec::new();
+        open_input(&args.flag_files_from)?.read_to_end(&mut list)?;
+        inputs.extend(parse_path_list(&list)?);
+    }
+    if !inputs.is_empty() || args.flag_files_from.is_some() {
         let mut did_error = false;
-        for input in args.arg_inputs.iter() {
+        for input in inputs.iter() {
             let input_str = input.to_string_lossy();
             // As with b2sum or sha1sum, the multi-arg hash loop prints errors and keeps going.
             // This is more convenient for the user in cases like `bao hash *`, where it's common
             // that some of the inputs will error on read e.g. because they're directories.
-            match hash_one(&Some(input.clone())) {
+            match hash_one(&Some(input.clone()), key.as_deref()) {
                 Ok(hash) => {
-                    if args.arg_inputs.len() > 1 {
+                    if inputs.len() > 1 || args.flag_files_from.is_some() {
                         println!("{}  {}", hash.to_hex(), input_str);
                     } else {
                         println!("{}", hash.to_hex());
@@ -132,12 +276,34 @@ fn hash(args: &Args) -> Result<(), Error> {
             std::process::exit(1);
         }
     } else {
-        let hash = hash_one(&None)?;
+        let hash = hash_one(&None, key.as_deref())?;
         println!("{}", hash.to_hex());
     }
     Ok(())
 }
 
+// If the list contains any NUL bytes, it's NUL-separated. Otherwise it's newline-separated. Empty
+// entries are skipped.
+fn parse_path_list(list: &[u8]) -> Result<Vec<PathBuf>, Error> {
+    let separator = if list.contains(&0) { b'\0' } else { b'\n' };
+    list.split(|&b| b == separator)
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, Error> {
+    let path = std::str::from_utf8(bytes).map_err(|_| err_msg("paths must be UTF-8"))?;
+    Ok(path.trim_end_matches('\r').into())
+}
+
 fn encode(args: &Args) -> Result<(), Error> {
     let mut input = open_input(&args.arg_input)?;
     let out_maybe_path = if args.flag_outboard.is_some() {
@@ -145,66 +311,280 @@ fn encode(args: &Args) -> Result<(), Error> {
     } else {
         &args.arg_output
     };
-    let output = open_output(out_maybe_path)?;
-    let mut encoder = if args.flag_outboard.is_some() {
-        bao::encode::Encoder::new_outboard(output.require_file()?)
+    let mut output = open_output(out_maybe_path)?;
+    let key = read_key(args)?;
+    if !output.is_seekable() {
+        return encode_to_pipe(input, output, args.flag_outboard.is_some(), key.as_deref());
+    }
+    let output_file = output.require_file()?;
+    let mut encoder = match (args.flag_outboard.is_some(), key.as_deref()) {
+        (false, None) => bao::encode::Encoder::new(output_file),
+        (true, None) => bao::encode::Encoder::new_outboard(output_file),
+        (false, Some(key)) => bao::encode::Encoder::new_keyed(output_file, key),
+        (true, Some(key)) => bao::encode::Encoder::new_outboard_keyed(output_file, key),
+    };
+    copy_reader_to_writer(&mut input, &mut encoder)?;
+    encoder.finalize()?;
+    drop(encoder);
+    output.finish()?;
+    Ok(())
+}
+
+// The Encoder has to seek back over its output to flip it into pre-order, which a pipe can't do.
+// Instead, build the outboard tree in memory, and then stream the combined encoding out of a
+// SliceExtractor covering all of the content, which has exactly the same layout. That means
+// reading the input twice, so it has to be a file. If the file changes in between, the chunks
+// won't match the tree, and decoding will fail.
+fn encode_to_pipe(
+    input: Input,
+    mut output: Output,
+    outboard_only: bool,
+    key: Option<&Key>,
+) -> Result<(), Error> {
+    let mut input = if outboard_only {
+        input
     } else {
-        bao::encode::Encoder::new(output.require_file()?)
+        Input::File(
+            input
+                .require_file()
+                .map_err(|_| err_msg("encoding to a pipe requires an input file"))?,
+        )
+    };
+    let outboard = io::Cursor::new(Vec::new());
+    let mut encoder = match key {
+        Some(key) => bao::encode::Encoder::new_outboard_keyed(outboard, key),
+        None => bao::encode::Encoder::new_outboard(outboard),
     };
     copy_reader_to_writer(&mut input, &mut encoder)?;
     encoder.finalize()?;
+    let content_len = encoder.content_len();
+    let mut outboard = encoder.into_inner();
+    if outboard_only {
+        output.write_all(outboard.get_ref())?;
+    } else {
+        let mut content = input.require_file()?;
+        content.seek(io::SeekFrom::Start(0))?;
+        outboard.set_position(0);
+        let mut extractor =
+            bao::encode::SliceExtractor::new_outboard(content, outboard, 0, content_len);
+        allow_broken_pipe(copy_reader_to_writer(&mut extractor, &mut output))?;
+    }
+    output.finish()?;
     Ok(())
 }
 
 fn decode(args: &Args) -> Result<(), Error> {
+    let hash = parse_hash(args)?;
+    let key = read_key(args)?;
+    let key = key.as_deref();
+    if args.flag_resume {
+        if args.flag_sparse {
+            return Err(err_msg("--sparse can't be used with --resume"));
+        }
+        return decode_resumable(args, &hash, key);
+    }
     let input = open_input(&args.arg_input)?;
     let mut output = open_output(&args.arg_output)?;
-    let hash = parse_hash(args)?;
+    let outboard_path = find_outboard(args);
     let outboard;
     let mut generic_decoder;
     let mut file_decoder;
-    let mut decoder: &mut dyn Read;
-    if args.flag_outboard.is_some() {
-        outboard = open_input(&args.flag_outboard)?;
+    let decoder: &mut dyn Read;
+    if outboard_path.is_some() {
+        outboard = open_input(&outboard_path)?;
         if let Some(offset) = args.flag_start {
-            file_decoder = bao::decode::Decoder::new_outboard(
-                input.require_file()?,
-                outboard.require_file()?,
-                &hash,
-            );
+            file_decoder =
+                outboard_decoder(input.require_file()?, outboard.require_file()?, &hash, key);
             file_decoder.seek(io::SeekFrom::Start(offset))?;
             decoder = &mut file_decoder;
         } else {
-            generic_decoder = bao::decode::Decoder::new_outboard(input, outboard, &hash);
+            generic_decoder = outboard_decoder(input, outboard, &hash, key);
             decoder = &mut generic_decoder;
         }
     } else {
         if let Some(offset) = args.flag_start {
-            file_decoder = bao::decode::Decoder::new(input.require_file()?, &hash);
+            file_decoder = combined_decoder(input.require_file()?, &hash, key);
             file_decoder.seek(io::SeekFrom::Start(offset))?;
             decoder = &mut file_decoder;
         } else {
-            generic_decoder = bao::decode::Decoder::new(input, &hash);
+            generic_decoder = combined_decoder(input, &hash, key);
             decoder = &mut generic_decoder;
         }
     }
-    if let Some(count) = args.flag_count {
-        let mut taker = decoder.take(count);
-        allow_broken_pipe(copy_reader_to_writer(&mut taker, &mut output))?;
+    let mut decoder = decoder.take(args.flag_count.unwrap_or(u64::MAX));
+    if args.flag_sparse {
+        let mut sparse_output = SparseFile::new(output.require_file()?);
+        copy_reader_to_writer(&mut decoder, &mut sparse_output)?;
+        sparse_output.finish()?;
     } else {
         allow_broken_pipe(copy_reader_to_writer(&mut decoder, &mut output))?;
     }
+    output.finish()?;
     Ok(())
 }
 
+// How many bytes to decode between checkpoints with --resume.
+const RESUME_CHECKPOINT_INTERVAL: u64 = 16 * 1024 * 1024;
+
+// With --resume, decoded output goes to `<output>.bao-partial`, and the number of bytes written so
+// far is checkpointed in `<output>.bao-resume`, along with the hash. A later run with the same hash
+// picks up at the checkpoint. Seeking the decoder there only re-verifies the parent nodes on the
+// path to that offset, not the content before it.
+fn decode_resumable(args: &Args, hash: &bao::Hash, key: Option<&Key>) -> Result<(), Error> {
+    if args.flag_start.is_some() || args.flag_count.is_some() {
+        return Err(err_msg("--resume can't be used with --start or --count"));
+    }
+    let output_path = path_if_some_and_not_dash(&args.arg_output)
+        .ok_or_else(|| err_msg("--resume requires an output file"))?;
+    // The partial output is renamed over the output at the end, so like open_output, resolve
+    // symlinks first and keep the existing file's permissions.
+    let (output_path, existing) = resolve_output(output_path)?;
+    if existing
+        .as_ref()
+        .is_some_and(|metadata| !metadata.is_file())
+    {
+        return Err(err_msg("--resume requires a regular output file"));
+    }
+    let partial_path = path_with_suffix(&output_path, ".bao-partial");
+    let checkpoint_path = path_with_suffix(&output_path, ".bao-resume");
+
+    let input = open_input(&args.arg_input)?.require_file()?;
+    let outboard_path = find_outboard(args);
+    let mut decoder = if outboard_path.is_some() {
+        let outboard = open_input(&outboard_path)?.require_file()?;
+        outboard_decoder(input, outboard, hash, key)
+    } else {
+        combined_decoder(input, hash, key)
+    };
+
+    let mut position = read_checkpoint(&checkpoint_path, hash)?.unwrap_or(0);
+    let mut partial = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&partial_path)?;
+    // If the partial output is missing or shorter than the checkpoint, the checkpoint is stale,
+    // and extending the file would fill it with unverified zeros. Start over instead.
+    if partial.metadata()?.len() < position {
+        position = 0;
+    }
+    // Anything past the checkpoint might not have been synced.
+    partial.set_len(position)?;
+    partial.seek(io::SeekFrom::Start(position))?;
+    decoder.seek(io::SeekFrom::Start(position))?;
+    loop {
+        let mut taker = (&mut decoder).take(RESUME_CHECKPOINT_INTERVAL);
+        let n = copy_reader_to_writer(&mut taker, &mut partial)?;
+        position += n;
+        partial.sync_data()?;
+        write_checkpoint(&checkpoint_path, hash, position)?;
+        if n < RESUME_CHECKPOINT_INTERVAL {
+            break;
+        }
+    }
+    if let Some(metadata) = &existing {
+        partial.set_permissions(metadata.permissions())?;
+    }
+    drop(partial);
+    fs::rename(&partial_path, &output_path)?;
+    fs::remove_file(&checkpoint_path)?;
+    Ok(())
+}
+
+// The checkpoint file contains the hash in hex and the decoded position, separated by a space. A
+// checkpoint for a different hash is ignored.
+fn read_checkpoint(path: &Path, hash: &bao::Hash) -> Result<Option<u64>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut fields = contents.split_whitespace();
+    if fields.next() != Some(hash.to_hex().as_str()) {
+        return Ok(None);
+    }
+    let position = fields
+        .next()
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| err_msg("invalid resume checkpoint"))?;
+    Ok(Some(position))
+}
+
+fn write_checkpoint(path: &Path, hash: &bao::Hash, position: u64) -> io::Result<()> {
+    let temp_path = path_with_suffix(path, ".tmp");
+    fs::write(&temp_path, format!("{} {}\n", hash.to_hex(), position))?;
+    fs::rename(&temp_path, path)
+}
+
+// Blocks of zeros this size and alignment are skipped rather than written. This matches the block
+// size of most filesystems.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+// A writer that seeks over zero blocks instead of writing them, leaving holes in the file. The
+// decoder returns one chunk at a time, so we collect whole blocks before checking them.
+struct SparseFile<'a> {
+    file: &'a mut File,
+    block: [u8; SPARSE_BLOCK_SIZE],
+    block_len: usize,
+    position: u64,
+}
+
+impl<'a> SparseFile<'a> {
+    fn new(file: &'a mut File) -> Self {
+        Self {
+            file,
+            block: [0; SPARSE_BLOCK_SIZE],
+            block_len: 0,
+            position: 0,
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let block = &self.block[..self.block_len];
+        if block.len() == SPARSE_BLOCK_SIZE && block.iter().all(|&b| b == 0) {
+            self.file
+                .seek(io::SeekFrom::Current(SPARSE_BLOCK_SIZE as i64))?;
+        } else {
+            self.file.write_all(block)?;
+        }
+        self.position += block.len() as u64;
+        self.block_len = 0;
+        Ok(())
+    }
+
+    // If the output ends in a hole, nothing has been written there yet. Setting the length
+    // creates it.
+    fn finish(mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.file.set_len(self.position)
+    }
+}
+
+impl Write for SparseFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), SPARSE_BLOCK_SIZE - self.block_len);
+        self.block[self.block_len..][..len].copy_from_slice(&buf[..len]);
+        self.block_len += len;
+        if self.block_len == SPARSE_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
 fn slice(args: &Args) -> Result<(), Error> {
     let input = open_input(&args.arg_input)?;
     let mut output = open_output(&args.arg_output)?;
     // Slice extraction requires seek.
+    let outboard_path = find_outboard(args);
     let outboard;
     let mut extractor;
-    if args.flag_outboard.is_some() {
-        outboard = open_input(&args.flag_outboard)?;
+    if outboard_path.is_some() {
+        outboard = open_input(&outboard_path)?;
         extractor = bao::encode::SliceExtractor::new_outboard(
             input.require_file()?,
             outboard.require_file()?,
@@ -216,18 +596,317 @@ fn slice(args: &Args) -> Result<(), Error> {
             bao::encode::SliceExtractor::new(input.require_file()?, args.arg_start, args.arg_count);
     }
     copy_reader_to_writer(&mut extractor, &mut output)?;
+    output.finish()?;
     Ok(())
 }
 
 fn decode_slice(args: &Args) -> Result<(), Error> {
     let input = open_input(&args.arg_input)?;
     let mut output = open_output(&args.arg_output)?;
-    let hash = parse_hash(&args)?;
-    let mut decoder = bao::decode::SliceDecoder::new(input, &hash, args.arg_start, args.arg_count);
+    let hash = parse_hash(args)?;
+    let key = read_key(args)?;
+    let mut decoder = match key.as_deref() {
+        Some(key) => {
+            bao::decode::SliceDecoder::new_keyed(input, &hash, key, args.arg_start, args.arg_count)
+        }
+        None => bao::decode::SliceDecoder::new(input, &hash, args.arg_start, args.arg_count),
+    };
     allow_broken_pipe(copy_reader_to_writer(&mut decoder, &mut output))?;
+    output.finish()?;
     Ok(())
 }
 
+fn combined_decoder<T: Read>(
+    input: T,
+    hash: &bao::Hash,
+    key: Option<&Key>,
+) -> bao::decode::Decoder<T, T> {
+    match key {
+        Some(key) => bao::decode::Decoder::new_keyed(input, hash, key),
+        None => bao::decode::Decoder::new(input, hash),
+    }
+}
+
+fn outboard_decoder<T: Read, O: Read>(
+    input: T,
+    outboard: O,
+    hash: &bao::Hash,
+    key: Option<&Key>,
+) -> bao::decode::Decoder<T, O> {
+    match key {
+        Some(key) => bao::decode::Decoder::new_outboard_keyed(input, outboard, hash, key),
+        None => bao::decode::Decoder::new_outboard(input, outboard, hash),
+    }
+}
+
+type Key = [u8; bao::KEY_SIZE];
+
+// The key is wiped from memory when the returned value is dropped, and so is every intermediate
+// buffer we read it through. (The library makes its own copies inside the hasher state, which we
+// can't reach from here.)
+fn read_key(args: &Args) -> Result<Option<Zeroizing<Key>>, Error> {
+    let mut key = Zeroizing::new([0; bao::KEY_SIZE]);
+    if let Some(ref path) = args.flag_key_file {
+        let mut file = File::open(path)?;
+        let mut contents = Zeroizing::new(Vec::with_capacity(bao::KEY_SIZE + 1));
+        (&mut file)
+            .take(bao::KEY_SIZE as u64 + 1)
+            .read_to_end(&mut contents)?;
+        if contents.len() != bao::KEY_SIZE {
+            return Err(err_msg("key file must be exactly 32 bytes"));
+        }
+        key.copy_from_slice(&contents);
+    } else if let Some(ref var) = args.flag_key_env {
+        let hex_key = Zeroizing::new(
+            std::env::var(var).map_err(|_| err_msg("key variable is missing or not unicode"))?,
+        );
+        hex::decode_to_slice(hex_key.trim(), &mut key[..])
+            .map_err(|_| err_msg("key variable must be 64 hex characters"))?;
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(key))
+}
+
+// Use the --outboard file if there is one. Otherwise look for a sidecar outboard next to the input
+// file, named `<input>.obao`.
+fn find_outboard(args: &Args) -> Option<PathBuf> {
+    if args.flag_outboard.is_some() {
+        return args.flag_outboard.clone();
+    }
+    sidecar_outboard(path_if_some_and_not_dash(&args.arg_input)?)
+}
+
+fn sidecar_outboard(input: &Path) -> Option<PathBuf> {
+    let sidecar = path_with_suffix(input, ".obao");
+    if sidecar.is_file() {
+        Some(sidecar)
+    } else {
+        None
+    }
+}
+
+fn verify(args: &Args) -> Result<(), Error> {
+    let jobs = match args.flag_jobs {
+        Some(0) => return Err(err_msg("--jobs must be at least 1")),
+        Some(jobs) => jobs,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let key = read_key(args)?;
+    // Workers take targets in order from a shared counter. Results are printed in the original
+    // order once everything is done.
+    let next_target = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<Result<(), Error>>>> =
+        args.arg_targets.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(args.arg_targets.len()) {
+            scope.spawn(|| loop {
+                let i = next_target.fetch_add(1, Ordering::Relaxed);
+                if i >= args.arg_targets.len() {
+                    return;
+                }
+                let result = verify_one(&args.arg_targets[i], key.as_deref());
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+    check_interrupted()?;
+    let mut failed = 0;
+    for (target, result) in args.arg_targets.iter().zip(results) {
+        let path = target.split_once(':').map_or(&**target, |(_, path)| path);
+        match result
+            .into_inner()
+            .unwrap()
+            .expect("every target is verified")
+        {
+            Ok(()) => println!("OK      {}", path),
+            Err(e) => {
+                failed += 1;
+                println!("FAILED  {}: {}", path, e);
+            }
+        }
+    }
+    println!(
+        "{} verified, {} failed",
+        args.arg_targets.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn verify_one(target: &str, key: Option<&Key>) -> Result<(), Error> {
+    let (hash_hex, path) = target
+        .split_once(':')
+        .ok_or_else(|| err_msg("target must be of the form <hash>:<path>"))?;
+    let hash = parse_hash_hex(hash_hex)?;
+    let path = Path::new(path);
+    let input = File::open(path)?;
+    match sidecar_outboard(path) {
+        Some(outboard_path) => {
+            let outboard = File::open(outboard_path)?;
+            let mut decoder = outboard_decoder(input, outboard, &hash, key);
+            copy_reader_to_writer(&mut decoder, &mut io::sink())?;
+        }
+        None => {
+            let mut decoder = combined_decoder(input, &hash, key);
+            copy_reader_to_writer(&mut decoder, &mut io::sink())?;
+        }
+    }
+    Ok(())
+}
+
+fn diff(args: &Args) -> Result<(), Error> {
+    let mut a = File::open(&args.arg_a)?;
+    let mut b = File::open(&args.arg_b)?;
+    let ranges = if args.flag_encoded || args.flag_outboards {
+        // Trees of different lengths have different shapes, so there's nothing to line up.
+        let a_len = read_len_header(&mut a)?;
+        let b_len = read_len_header(&mut b)?;
+        if a_len != b_len {
+            let whole = 0..cmp::max(a_len, b_len);
+            vec![whole]
+        } else if args.flag_encoded {
+            bao::encode::diff_encoded(a, b)?
+        } else {
+            bao::encode::diff_outboards(a, b)?
+        }
+    } else {
+        let a_len = a.metadata()?.len();
+        let b_len = b.metadata()?.len();
+        let common_len = cmp::min(a_len, b_len);
+        let (a_outboard, b_outboard) = if a_len == b_len {
+            (
+                open_or_build_outboard(&args.arg_a, &mut a)?,
+                open_or_build_outboard(&args.arg_b, &mut b)?,
+            )
+        } else {
+            (
+                build_outboard(&mut a, common_len)?,
+                build_outboard(&mut b, common_len)?,
+            )
+        };
+        let mut ranges = bao::encode::diff_outboards(a_outboard, b_outboard)?;
+        // Without parent nodes to compare, a single chunk has to be compared directly.
+        if common_len <= CHUNK_SIZE && !ranges.is_empty() {
+            let (mut a_chunk, mut b_chunk) = (Vec::new(), Vec::new());
+            a.seek(io::SeekFrom::Start(0))?;
+            (&mut a).take(common_len).read_to_end(&mut a_chunk)?;
+            b.seek(io::SeekFrom::Start(0))?;
+            (&mut b).take(common_len).read_to_end(&mut b_chunk)?;
+            if a_chunk == b_chunk {
+                ranges.clear();
+            }
+        }
+        // Past the common content, everything differs, starting with the chunk the shorter file
+        // ends in.
+        if a_len != b_len {
+            let tail_start = common_len / CHUNK_SIZE * CHUNK_SIZE;
+            let tail_end = cmp::max(a_len, b_len);
+            match ranges.last_mut() {
+                Some(last) if last.end >= tail_start => last.end = tail_end,
+                _ => ranges.push(tail_start..tail_end),
+            }
+        }
+        ranges
+    };
+    if args.flag_json {
+        let objects: Vec<String> = ranges
+            .iter()
+            .map(|range| format!("{{\"start\":{},\"end\":{}}}", range.start, range.end))
+            .collect();
+        println!("[{}]", objects.join(","));
+    } else if args.flag_stat {
+        let total: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        println!("{} ranges, {} bytes differ", ranges.len(), total);
+    } else {
+        for range in &ranges {
+            println!("{}-{}", range.start, range.end);
+        }
+    }
+    if !ranges.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn dedup_stats(args: &Args) -> Result<(), Error> {
+    let depth = args.flag_depth.unwrap_or(8);
+    if depth == 0 {
+        return Err(err_msg("--depth must be at least 1"));
+    }
+    let outboards = args
+        .arg_outboards
+        .iter()
+        .map(File::open)
+        .collect::<io::Result<Vec<File>>>()?;
+    let stats = bao::encode::dedup_stats(outboards, depth)?;
+    let percent = if stats.total_bytes > 0 {
+        100.0 * stats.savings() as f64 / stats.total_bytes as f64
+    } else {
+        0.0
+    };
+    println!("total bytes:       {}", stats.total_bytes);
+    println!("unique bytes:      {}", stats.unique_bytes);
+    println!("potential savings: {} ({:.1}%)", stats.savings(), percent);
+    println!(
+        "subtrees:          {} ({} unique)",
+        stats.total_subtrees, stats.unique_subtrees
+    );
+    Ok(())
+}
+
+// Use the sidecar outboard if there is one. Otherwise encode one in memory.
+// A sidecar outboard is only used if it matches the content, so that a stale one can't hide a
+// difference. Checking it takes a pass over the content, but it's a fast one, and it doesn't hold
+// a rebuilt outboard in memory.
+fn open_or_build_outboard(path: &Path, content: &mut File) -> Result<Input, Error> {
+    if let Some(outboard_path) = sidecar_outboard(path) {
+        let mut outboard = File::open(outboard_path)?;
+        if sidecar_matches(path, content, &mut outboard)? {
+            outboard.seek(io::SeekFrom::Start(0))?;
+            return Ok(Input::File(outboard));
+        }
+    }
+    let content_len = content.metadata()?.len();
+    build_outboard(content, content_len)
+}
+
+// Build an outboard in memory for the first `len` bytes of the content.
+fn build_outboard(content: &mut File, len: u64) -> Result<Input, Error> {
+    let mut encoder = bao::encode::Encoder::new_outboard(io::Cursor::new(Vec::new()));
+    copy_reader_to_writer(&mut content.take(len), &mut encoder)?;
+    encoder.finalize()?;
+    Ok(Input::Memory(encoder.into_inner()))
+}
+
+// Read the content length from the start of an encoding or outboard, leaving the file positioned
+// after it.
+fn read_len_header(encoding: &mut File) -> io::Result<u64> {
+    let mut header = [0; 8];
+    encoding.read_exact(&mut header)?;
+    Ok(u64::from_le_bytes(header))
+}
+
+fn sidecar_matches(path: &Path, content: &mut File, outboard: &mut File) -> Result<bool, Error> {
+    let content_len = content.metadata()?.len();
+    if !matches!(read_len_header(outboard), Ok(len) if len == content_len) {
+        return Ok(false);
+    }
+    // A single chunk has no parent nodes, and diff compares it directly.
+    if content_len <= CHUNK_SIZE {
+        return Ok(true);
+    }
+    outboard.seek(io::SeekFrom::Start(0))?;
+    let root = match bao::decode::root_hash(&mut *outboard) {
+        Ok(Some(root)) => root,
+        _ => return Ok(false),
+    };
+    Ok(root == hash_one(&Some(path.to_owned()), None)?)
+}
+
 fn open_input(maybe_path: &Option<PathBuf>) -> Result<Input, Error> {
     Ok(
         if let Some(ref path) = path_if_some_and_not_dash(maybe_path) {
@@ -241,13 +920,14 @@ fn open_input(maybe_path: &Option<PathBuf>) -> Result<Input, Error> {
 enum Input {
     Stdin,
     File(File),
+    Memory(io::Cursor<Vec<u8>>),
 }
 
 impl Input {
     fn require_file(self) -> Result<File, Error> {
         match self {
-            Input::Stdin => Err(err_msg(format!("input must be a real file"))),
             Input::File(file) => Ok(file),
+            _ => Err(err_msg("input must be a real file")),
         }
     }
 }
@@ -257,34 +937,108 @@ impl Read for Input {
         match *self {
             Input::Stdin => io::stdin().read(buf),
             Input::File(ref mut file) => file.read(buf),
+            Input::Memory(ref mut cursor) => cursor.read(buf),
         }
     }
 }
 
+impl Seek for Input {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match *self {
+            Input::Stdin => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stdin isn't seekable",
+            )),
+            Input::File(ref mut file) => file.seek(pos),
+            Input::Memory(ref mut cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+// Output files are written under a temporary name in the same directory, and renamed into place
+// by Output::finish once the command has succeeded. That way an interrupted or failed run never
+// leaves behind a partial file that looks complete. Outputs that aren't regular files, like
+// /dev/null or a named pipe, are written directly. If the output is a symlink, the file it points
+// to is replaced rather than the link itself, and an existing file's permissions carry over to
+// its replacement.
 fn open_output(maybe_path: &Option<PathBuf>) -> Result<Output, Error> {
-    if let Some(ref path) = path_if_some_and_not_dash(maybe_path) {
-        // Both reading and writing permissions are required for MmapMut.
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)?;
-        return Ok(Output::File(file));
+    let path = match path_if_some_and_not_dash(maybe_path) {
+        Some(path) => path,
+        None => return Ok(Output::Stdout),
+    };
+    let (path, existing) = resolve_output(path)?;
+    let path = &*path;
+    let is_special = existing
+        .as_ref()
+        .is_some_and(|metadata| !metadata.is_file());
+    let pending = if is_special {
+        None
+    } else {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| err_msg("output path must name a file"))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".bao-{}.tmp", std::process::id()));
+        Some(PendingRename {
+            temp_path: path.with_file_name(temp_name),
+            final_path: path.to_owned(),
+        })
+    };
+    let open_path = pending.as_ref().map_or(path, |p| &p.temp_path);
+    // Both reading and writing permissions are required for MmapMut.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(open_path)?;
+    if let (Some(metadata), Some(_)) = (&existing, &pending) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    Ok(Output::File(file, pending))
+}
+
+// If the output is a symlink, it's the file the link points to that gets replaced. This returns
+// the path to replace, along with the metadata of the file that's already there, if any.
+fn resolve_output(path: &Path) -> Result<(PathBuf, Option<fs::Metadata>), Error> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok((fs::canonicalize(path)?, Some(metadata))),
+        Err(_) => Ok((path.to_owned(), None)),
     }
-    Ok(Output::Stdout)
 }
 
 enum Output {
     Stdout,
-    File(File),
+    File(File, Option<PendingRename>),
 }
 
 impl Output {
-    fn require_file(self) -> Result<File, Error> {
+    fn require_file(&mut self) -> Result<&mut File, Error> {
         match self {
-            Output::Stdout => Err(err_msg(format!("output must be a real file"))),
-            Output::File(file) => Ok(file),
+            Output::Stdout => Err(err_msg("output must be a real file")),
+            Output::File(file, _) => Ok(file),
+        }
+    }
+
+    // Special files like pipes and terminals are opened without a temporary file, and they
+    // generally can't seek.
+    fn is_seekable(&self) -> bool {
+        matches!(self, Output::File(_, Some(_)))
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::File(file, pending) => {
+                if let Some(mut pending) = pending {
+                    file.sync_all()?;
+                    drop(file);
+                    fs::rename(&pending.temp_path, &pending.final_path)?;
+                    pending.temp_path.clear();
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -293,18 +1047,38 @@ impl Write for Output {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match *self {
             Output::Stdout => io::stdout().write(buf),
-            Output::File(ref mut file) => file.write(buf),
+            Output::File(ref mut file, _) => file.write(buf),
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Output::Stdout => io::stdout().flush(),
-            Output::File(ref mut file) => file.flush(),
+            Output::File(ref mut file, _) => file.flush(),
+        }
+    }
+}
+
+struct PendingRename {
+    temp_path: PathBuf,
+    final_path: PathBuf,
+}
+
+impl Drop for PendingRename {
+    fn drop(&mut self) {
+        // The temp path is cleared after a successful rename.
+        if !self.temp_path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.temp_path);
         }
     }
 }
 
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
 fn path_if_some_and_not_dash(maybe_path: &Option<PathBuf>) -> Option<&Path> {
     if let Some(ref path) = maybe_path {
         if path == Path::new("-") {
@@ -319,15 +1093,15 @@ fn path_if_some_and_not_dash(maybe_path: &Option<PathBuf>) -> Option<&Path> {
 
 fn maybe_memmap_input(input: &Input) -> Result<Option<memmap::Mmap>, Error> {
     let in_file = match *input {
-        Input::Stdin => return Ok(None),
         Input::File(ref file) => file,
+        _ => return Ok(None),
     };
     let metadata = in_file.metadata()?;
     let file_size = metadata.len();
     Ok(if !metadata.is_file() {
         // Not a real file.
         None
-    } else if file_size > isize::max_value() as u64 {
+    } else if file_size > isize::MAX as u64 {
         // Too long to safely map. https://github.com/danburkert/memmap-rs/issues/69
         None
     } else if file_size == 0 {
@@ -342,14 +1116,18 @@ fn maybe_memmap_input(input: &Input) -> Result<Option<memmap::Mmap>, Error> {
         let map = unsafe {
             memmap::MmapOptions::new()
                 .len(metadata.len() as usize)
-                .map(&in_file)?
+                .map(in_file)?
         };
         Some(map)
     })
 }
 
 fn parse_hash(args: &Args) -> Result<bao::Hash, Error> {
-    let hash_vec = hex::decode(&args.arg_hash).map_err(|_| err_msg("invalid hex"))?;
+    parse_hash_hex(&args.arg_hash)
+}
+
+fn parse_hash_hex(hash_hex: &str) -> Result<bao::Hash, Error> {
+    let hash_vec = hex::decode(hash_hex).map_err(|_| err_msg("invalid hex"))?;
     if hash_vec.len() != bao::HASH_SIZE {
         return Err(err_msg("wrong length hash"));
     };
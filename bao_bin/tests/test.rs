@@ -56,7 +56,7 @@ fn test_hash_many() {
 fn assert_hash_mismatch(output: &std::process::Output) {
     assert!(!output.status.success());
     let stderr = String::from_utf8_lossy(&output.stderr);
-    assert!(stderr.contains(bao::decode::Error::HashMismatch.to_string().as_str()));
+    assert!(stderr.contains("hash mismatch"));
 }
 
 #[test]
@@ -124,6 +124,41 @@ fn test_encode_decode_combined() {
     assert_eq!(input_bytes[1..2], *partial_output);
 }
 
+#[test]
+fn test_encode_to_pipe() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let mut input_bytes = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    fs::write(&input_path, &input_bytes).unwrap();
+    let (expected_encoded, _) = bao::encode::encode(&input_bytes);
+    let (expected_outboard, _) = bao::encode::outboard(&input_bytes);
+
+    let output = cmd!(bao_exe(), "encode", &input_path, "-")
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert_eq!(expected_encoded, output.stdout);
+
+    // An outboard encoding doesn't need to read the input twice, so stdin works too.
+    let output = cmd!(bao_exe(), "encode", "-", "--outboard", "-")
+        .stdin_bytes(&*input_bytes)
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert_eq!(expected_outboard, output.stdout);
+
+    let output = cmd!(bao_exe(), "encode", "-", "-")
+        .stdin_bytes(&*input_bytes)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
 #[test]
 fn test_encode_decode_outboard() {
     let dir = tempdir().unwrap();
@@ -277,3 +312,720 @@ fn test_slice() {
     .unwrap();
     assert_hash_mismatch(&output);
 }
+
+#[test]
+fn test_keyed() {
+    let key = [42; bao::KEY_SIZE];
+    let hex_key = hex::encode(key);
+    let input_bytes = &b"abc"[..];
+    let dir = tempdir().unwrap();
+    let key_path = dir.path().join("key");
+    fs::write(&key_path, key).unwrap();
+    let input_path = dir.path().join("input");
+    fs::write(&input_path, input_bytes).unwrap();
+
+    let expected = blake3::keyed_hash(&key, input_bytes).to_hex();
+    let output = cmd!(bao_exe(), "hash", "--key-file", &key_path)
+        .stdin_bytes(input_bytes)
+        .read()
+        .unwrap();
+    assert_eq!(&*expected, &*output);
+    let output = cmd!(bao_exe(), "hash", "--key-env", "BAO_TEST_KEY")
+        .env("BAO_TEST_KEY", &hex_key)
+        .stdin_bytes(input_bytes)
+        .read()
+        .unwrap();
+    assert_eq!(&*expected, &*output);
+
+    let encoded_path = dir.path().join("encoded");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        &encoded_path,
+        "--key-file",
+        &key_path
+    )
+    .run()
+    .unwrap();
+    let decoded_bytes = cmd!(
+        bao_exe(),
+        "decode",
+        &*expected,
+        &encoded_path,
+        "--key-env",
+        "BAO_TEST_KEY"
+    )
+    .env("BAO_TEST_KEY", &hex_key)
+    .stdout_capture()
+    .run()
+    .unwrap()
+    .stdout;
+    assert_eq!(input_bytes, &*decoded_bytes);
+
+    // Decoding without the key fails.
+    let output = cmd!(bao_exe(), "decode", &*expected, &encoded_path)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_hash_mismatch(&output);
+
+    // So does a key file of the wrong length.
+    fs::write(&key_path, &key[1..]).unwrap();
+    let output = cmd!(bao_exe(), "hash", "--key-file", &key_path)
+        .stdin_bytes(input_bytes)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_sidecar_outboard() {
+    let dir = tempdir().unwrap();
+    let input_path = dir.path().join("input");
+    let input_bytes = vec![0xab; 10_000];
+    fs::write(&input_path, &input_bytes).unwrap();
+    let input_hash = cmd!(bao_exe(), "hash", &input_path).read().unwrap();
+    let outboard_path = dir.path().join("input.obao");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &input_path,
+        "--outboard",
+        &outboard_path
+    )
+    .run()
+    .unwrap();
+
+    // Decoding finds input.obao without --outboard.
+    let decoded_bytes = cmd!(bao_exe(), "decode", &input_hash, &input_path)
+        .stdout_capture()
+        .run()
+        .unwrap()
+        .stdout;
+    assert_eq!(input_bytes, decoded_bytes);
+
+    // So does slicing.
+    let slice_bytes = cmd!(bao_exe(), "slice", "5000", "100", &input_path)
+        .stdout_capture()
+        .run()
+        .unwrap()
+        .stdout;
+    let decoded = cmd!(bao_exe(), "decode-slice", &input_hash, "5000", "100")
+        .stdin_bytes(slice_bytes)
+        .stdout_capture()
+        .run()
+        .unwrap()
+        .stdout;
+    assert_eq!(&input_bytes[5000..5100], &*decoded);
+}
+
+#[test]
+fn test_failed_decode_leaves_no_output() {
+    let dir = tempdir().unwrap();
+    let input_bytes = vec![0xcd; 10_000];
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*input_bytes)
+        .run()
+        .unwrap();
+
+    // Decoding with the wrong hash must not create the output file, or leave a temp file behind.
+    let decoded_path = dir.path().join("decoded");
+    let zero_hash = "0".repeat(2 * bao::HASH_SIZE);
+    let output = cmd!(
+        bao_exe(),
+        "decode",
+        &zero_hash,
+        &encoded_path,
+        &decoded_path
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert_hash_mismatch(&output);
+    let mut entries: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+    assert_eq!(vec!["encoded"], entries);
+
+    // An existing file is left untouched by a failed decode.
+    fs::write(&decoded_path, b"old contents").unwrap();
+    cmd!(
+        bao_exe(),
+        "decode",
+        &zero_hash,
+        &encoded_path,
+        &decoded_path
+    )
+    .stdout_capture()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert_eq!(b"old contents", &*fs::read(&decoded_path).unwrap());
+}
+
+#[test]
+fn test_hash_files_from() {
+    let dir = tempdir().unwrap();
+    let file1 = dir.path().join("file 1");
+    fs::write(&file1, b"foo").unwrap();
+    let file2 = dir.path().join("file2");
+    fs::write(&file2, b"bar").unwrap();
+    let expected = format!(
+        "{}  {}\n{}  {}",
+        blake3::hash(b"foo").to_hex(),
+        file1.to_string_lossy(),
+        blake3::hash(b"bar").to_hex(),
+        file2.to_string_lossy(),
+    );
+
+    // Newline-separated, from a file.
+    let list_path = dir.path().join("list");
+    let list = format!("{}\n{}\n", file1.to_string_lossy(), file2.to_string_lossy());
+    fs::write(&list_path, list).unwrap();
+    let output = cmd!(bao_exe(), "hash", "--files-from", &list_path)
+        .read()
+        .unwrap();
+    assert_eq!(expected, output);
+
+    // NUL-separated, from stdin.
+    let list = format!("{}\0{}\0", file1.to_string_lossy(), file2.to_string_lossy());
+    let output = cmd!(bao_exe(), "hash", "--files-from", "-")
+        .stdin_bytes(list)
+        .read()
+        .unwrap();
+    assert_eq!(expected, output);
+
+    // Even a single file from the list is printed with its name.
+    let output = cmd!(bao_exe(), "hash", "--files-from", "-")
+        .stdin_bytes(file2.to_string_lossy().as_bytes())
+        .read()
+        .unwrap();
+    assert_eq!(
+        format!(
+            "{}  {}",
+            blake3::hash(b"bar").to_hex(),
+            file2.to_string_lossy()
+        ),
+        output
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_through_symlink_keeps_permissions() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = tempdir().unwrap();
+    let input_bytes = vec![0xcd; 10_000];
+    let hash = blake3::hash(&input_bytes).to_hex();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*input_bytes)
+        .run()
+        .unwrap();
+    let target_path = dir.path().join("target");
+    fs::write(&target_path, b"old contents").unwrap();
+    fs::set_permissions(&target_path, fs::Permissions::from_mode(0o640)).unwrap();
+    let link_path = dir.path().join("link");
+    symlink(&target_path, &link_path).unwrap();
+
+    cmd!(bao_exe(), "decode", &*hash, &encoded_path, &link_path)
+        .run()
+        .unwrap();
+    assert!(fs::symlink_metadata(&link_path)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(input_bytes, fs::read(&target_path).unwrap());
+    let mode = fs::metadata(&target_path).unwrap().permissions().mode();
+    assert_eq!(0o640, mode & 0o777);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_resume_through_symlink_keeps_permissions() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = tempdir().unwrap();
+    let input_bytes = vec![0xcd; 10_000];
+    let hash = blake3::hash(&input_bytes).to_hex();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*input_bytes)
+        .run()
+        .unwrap();
+    let target_dir = dir.path().join("sub");
+    fs::create_dir(&target_dir).unwrap();
+    let target_path = target_dir.join("target");
+    fs::write(&target_path, b"old contents").unwrap();
+    fs::set_permissions(&target_path, fs::Permissions::from_mode(0o640)).unwrap();
+    let link_path = dir.path().join("link");
+    symlink(&target_path, &link_path).unwrap();
+
+    // The resume files live next to the file the link points to, so an earlier run's checkpoint
+    // there is picked up and then cleaned up.
+    let partial_path = target_dir.join("target.bao-partial");
+    let checkpoint_path = target_dir.join("target.bao-resume");
+    fs::write(&partial_path, &input_bytes[..4096]).unwrap();
+    fs::write(&checkpoint_path, format!("{} 4096\n", hash)).unwrap();
+
+    cmd!(
+        bao_exe(),
+        "decode",
+        &*hash,
+        &encoded_path,
+        &link_path,
+        "--resume"
+    )
+    .run()
+    .unwrap();
+    assert!(fs::symlink_metadata(&link_path)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(input_bytes, fs::read(&target_path).unwrap());
+    let mode = fs::metadata(&target_path).unwrap().permissions().mode();
+    assert_eq!(0o640, mode & 0o777);
+    assert!(!partial_path.exists());
+    assert!(!checkpoint_path.exists());
+    assert_eq!(1, fs::read_dir(&target_dir).unwrap().count());
+}
+
+#[test]
+fn test_decode_resume() {
+    let dir = tempdir().unwrap();
+    let mut input_bytes = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    let hash = cmd!(bao_exe(), "hash")
+        .stdin_bytes(&*input_bytes)
+        .read()
+        .unwrap();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*input_bytes)
+        .run()
+        .unwrap();
+    let decoded_path = dir.path().join("decoded");
+    let partial_path = dir.path().join("decoded.bao-partial");
+    let checkpoint_path = dir.path().join("decoded.bao-resume");
+
+    // An uninterrupted run cleans up after itself.
+    cmd!(
+        bao_exe(),
+        "decode",
+        &hash,
+        &encoded_path,
+        &decoded_path,
+        "--resume"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(input_bytes, fs::read(&decoded_path).unwrap());
+    assert!(!partial_path.exists());
+    assert!(!checkpoint_path.exists());
+
+    // Simulate an interrupted run, with unsynced garbage after the checkpoint.
+    fs::remove_file(&decoded_path).unwrap();
+    let mut partial = input_bytes[..50_000].to_vec();
+    partial.extend_from_slice(&[0xff; 1000]);
+    fs::write(&partial_path, partial).unwrap();
+    fs::write(&checkpoint_path, format!("{} 50000\n", hash)).unwrap();
+    cmd!(
+        bao_exe(),
+        "decode",
+        &hash,
+        &encoded_path,
+        &decoded_path,
+        "--resume"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(input_bytes, fs::read(&decoded_path).unwrap());
+    assert!(!partial_path.exists());
+    assert!(!checkpoint_path.exists());
+
+    // A checkpoint without its partial output is ignored, rather than trusting zeros.
+    fs::remove_file(&decoded_path).unwrap();
+    fs::write(&checkpoint_path, format!("{} 50000\n", hash)).unwrap();
+    cmd!(
+        bao_exe(),
+        "decode",
+        &hash,
+        &encoded_path,
+        &decoded_path,
+        "--resume"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(input_bytes, fs::read(&decoded_path).unwrap());
+    assert!(!partial_path.exists());
+    assert!(!checkpoint_path.exists());
+}
+
+#[test]
+fn test_verify() {
+    let dir = tempdir().unwrap();
+    let mut targets = Vec::new();
+    for i in 0..5 {
+        let input_bytes = vec![i as u8; 3000 * i];
+        let hash = blake3::hash(&input_bytes).to_hex();
+        let encoded_path = dir.path().join(format!("encoded{}", i));
+        cmd!(bao_exe(), "encode", "-", &encoded_path)
+            .stdin_bytes(&*input_bytes)
+            .run()
+            .unwrap();
+        targets.push(format!("{}:{}", hash, encoded_path.to_string_lossy()));
+    }
+    // One target is content with a sidecar outboard.
+    let content_path = dir.path().join("content");
+    fs::write(&content_path, b"foo").unwrap();
+    let outboard_path = dir.path().join("content.obao");
+    cmd!(
+        bao_exe(),
+        "encode",
+        &content_path,
+        "--outboard",
+        &outboard_path
+    )
+    .run()
+    .unwrap();
+    let foo_hash = blake3::hash(b"foo").to_hex();
+    targets.push(format!("{}:{}", foo_hash, content_path.to_string_lossy()));
+
+    let mut command = vec!["verify".to_string(), "--jobs=3".to_string()];
+    command.extend(targets.iter().cloned());
+    let output = cmd(bao_exe(), &command).read().unwrap();
+    assert!(output.ends_with("6 verified, 0 failed"));
+    assert_eq!(6, output.matches("OK  ").count());
+
+    // Corrupt one target and check that it's reported.
+    fs::write(&content_path, b"bar").unwrap();
+    let output = cmd(bao_exe(), &command)
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("FAILED  {}", content_path.to_string_lossy())));
+    assert!(stdout.ends_with("5 verified, 1 failed\n"));
+}
+
+#[test]
+fn test_verify_keyed() {
+    let key = [42; bao::KEY_SIZE];
+    let dir = tempdir().unwrap();
+    let key_path = dir.path().join("key");
+    fs::write(&key_path, key).unwrap();
+    let input_bytes = vec![7; 5000];
+    let hash = blake3::keyed_hash(&key, &input_bytes).to_hex();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(
+        bao_exe(),
+        "encode",
+        "-",
+        &encoded_path,
+        "--key-file",
+        &key_path
+    )
+    .stdin_bytes(&*input_bytes)
+    .run()
+    .unwrap();
+    let content_path = dir.path().join("content");
+    fs::write(&content_path, &input_bytes).unwrap();
+    cmd!(
+        bao_exe(),
+        "encode",
+        &content_path,
+        "--outboard",
+        dir.path().join("content.obao"),
+        "--key-file",
+        &key_path
+    )
+    .run()
+    .unwrap();
+    let targets = [
+        format!("{}:{}", hash, encoded_path.to_string_lossy()),
+        format!("{}:{}", hash, content_path.to_string_lossy()),
+    ];
+
+    let output = cmd!(
+        bao_exe(),
+        "verify",
+        &targets[0],
+        &targets[1],
+        "--key-file",
+        &key_path
+    )
+    .read()
+    .unwrap();
+    assert!(output.ends_with("2 verified, 0 failed"));
+
+    // Without the key, both targets fail.
+    let output = cmd!(bao_exe(), "verify", &targets[0], &targets[1])
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("0 verified, 2 failed\n"));
+}
+
+#[test]
+fn test_diff() {
+    let dir = tempdir().unwrap();
+    let mut a_bytes = vec![0; 100_000];
+    rand::thread_rng().fill_bytes(&mut a_bytes);
+    let mut b_bytes = a_bytes.clone();
+    b_bytes[50_000] ^= 1;
+    let a_path = dir.path().join("a");
+    fs::write(&a_path, &a_bytes).unwrap();
+    let b_path = dir.path().join("b");
+    fs::write(&b_path, &b_bytes).unwrap();
+
+    // Identical files.
+    let output = cmd!(bao_exe(), "diff", &a_path, &a_path).read().unwrap();
+    assert_eq!("", output);
+
+    // One differing chunk, with a sidecar outboard for one of the files.
+    cmd!(
+        bao_exe(),
+        "encode",
+        &a_path,
+        "--outboard",
+        dir.path().join("a.obao")
+    )
+    .run()
+    .unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_path, &b_path)
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("49152-50176\n", String::from_utf8_lossy(&output.stdout));
+    let output = cmd!(bao_exe(), "diff", &a_path, &b_path, "--stat")
+        .unchecked()
+        .read()
+        .unwrap();
+    assert_eq!("1 ranges, 1024 bytes differ", output);
+
+    // The same comparison on combined encodings, with JSON output.
+    let a_encoded = dir.path().join("a.bao");
+    cmd!(bao_exe(), "encode", &a_path, &a_encoded)
+        .run()
+        .unwrap();
+    let b_encoded = dir.path().join("b.bao");
+    cmd!(bao_exe(), "encode", &b_path, &b_encoded)
+        .run()
+        .unwrap();
+    let output = cmd!(
+        bao_exe(),
+        "diff",
+        &a_encoded,
+        &b_encoded,
+        "--encoded",
+        "--json"
+    )
+    .unchecked()
+    .read()
+    .unwrap();
+    assert_eq!(r#"[{"start":49152,"end":50176}]"#, output);
+
+    // A stale sidecar outboard is ignored. Here b.obao describes a's content, and if it were used,
+    // the files would look identical.
+    let b_outboard = dir.path().join("b.obao");
+    fs::copy(dir.path().join("a.obao"), &b_outboard).unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_path, &b_path)
+        .unchecked()
+        .read()
+        .unwrap();
+    assert_eq!("49152-50176", output);
+
+    // Two outboards can be compared without their content.
+    cmd!(bao_exe(), "encode", &b_path, "--outboard", &b_outboard)
+        .run()
+        .unwrap();
+    let output = cmd!(
+        bao_exe(),
+        "diff",
+        dir.path().join("a.obao"),
+        &b_outboard,
+        "--outboards"
+    )
+    .unchecked()
+    .read()
+    .unwrap();
+    assert_eq!("49152-50176", output);
+    fs::remove_file(&b_outboard).unwrap();
+
+    // Files of different lengths differ in their common content and everything after it,
+    // starting from the chunk where the shorter one ends.
+    let c_path = dir.path().join("c");
+    fs::write(&c_path, &b_bytes[..70_000]).unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_path, &c_path)
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(
+        "49152-50176\n69632-100000\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    // Encodings of different lengths differ everywhere.
+    let c_encoded = dir.path().join("c.bao");
+    cmd!(bao_exe(), "encode", &c_path, &c_encoded)
+        .run()
+        .unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_encoded, &c_encoded, "--encoded")
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("0-100000\n", String::from_utf8_lossy(&output.stdout));
+
+    // Errors exit with status 2.
+    let output = cmd!(bao_exe(), "diff", &a_path, dir.path().join("missing"))
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert_eq!(Some(2), output.status.code());
+
+    // Small files are compared directly.
+    fs::write(&a_path, b"foo").unwrap();
+    fs::write(&b_path, b"foo").unwrap();
+    fs::remove_file(dir.path().join("a.obao")).unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_path, &b_path).read().unwrap();
+    assert_eq!("", output);
+    fs::write(&b_path, b"fo").unwrap();
+    let output = cmd!(bao_exe(), "diff", &a_path, &b_path)
+        .unchecked()
+        .read()
+        .unwrap();
+    assert_eq!("0-3", output);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupted_encode_cleans_up() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let dir = tempdir().unwrap();
+    let encoded_path = dir.path().join("encoded");
+    let mut child = Command::new(bao_exe())
+        .args(["encode", "-"])
+        .arg(&encoded_path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&[0; 100_000]).unwrap();
+    // Give the encoder time to start and consume some input before we interrupt it.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    cmd!("kill", "-INT", child.id().to_string()).run().unwrap();
+    // The encoder notices the signal once its blocking read returns. It might exit before it's
+    // read everything we write, so a broken pipe here is expected.
+    if let Err(e) = stdin.write_all(&[0; 100_000]) {
+        assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+    }
+    drop(stdin);
+    let status = child.wait().unwrap();
+    assert_eq!(Some(130), status.code());
+    assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_dedup_stats() {
+    let dir = tempdir().unwrap();
+    let mut input_bytes = vec![0; 1 << 20];
+    rand::thread_rng().fill_bytes(&mut input_bytes);
+    let mut outboards = Vec::new();
+    for i in 0..2 {
+        let outboard_path = dir.path().join(format!("outboard{}", i));
+        input_bytes[0] ^= i;
+        cmd!(bao_exe(), "encode", "-", "--outboard", &outboard_path)
+            .stdin_bytes(&*input_bytes)
+            .run()
+            .unwrap();
+        outboards.push(outboard_path);
+    }
+    // At depth 2 the files are split into quarters, and only the first quarter differs.
+    let output = cmd!(
+        bao_exe(),
+        "dedup-stats",
+        "--depth=2",
+        &outboards[0],
+        &outboards[1]
+    )
+    .read()
+    .unwrap();
+    let expected = format!(
+        "total bytes:       {}\nunique bytes:      {}\npotential savings: {} (37.5%)\nsubtrees:          8 (5 unique)",
+        2 << 20,
+        5 << 18,
+        3 << 18,
+    );
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn test_decode_sparse() {
+    let dir = tempdir().unwrap();
+    // Mostly zeros, with some data in the middle and a partial block at the end.
+    let mut input_bytes = vec![0; 1_000_000];
+    input_bytes[500_000..500_100].copy_from_slice(&[0xab; 100]);
+    let hash = blake3::hash(&input_bytes).to_hex();
+    let encoded_path = dir.path().join("encoded");
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*input_bytes)
+        .run()
+        .unwrap();
+    let decoded_path = dir.path().join("decoded");
+    cmd!(
+        bao_exe(),
+        "decode",
+        &*hash,
+        &encoded_path,
+        &decoded_path,
+        "--sparse"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(input_bytes, fs::read(&decoded_path).unwrap());
+
+    // Trailing zeros become a hole too.
+    let all_zeros = vec![0; 100_000];
+    let hash = blake3::hash(&all_zeros).to_hex();
+    cmd!(bao_exe(), "encode", "-", &encoded_path)
+        .stdin_bytes(&*all_zeros)
+        .run()
+        .unwrap();
+    cmd!(
+        bao_exe(),
+        "decode",
+        &*hash,
+        &encoded_path,
+        &decoded_path,
+        "--sparse"
+    )
+    .run()
+    .unwrap();
+    assert_eq!(all_zeros, fs::read(&decoded_path).unwrap());
+}
@@ -37,7 +37,9 @@
 
 use crate::encode;
 use crate::encode::NextRead;
-use crate::{Finalization, Hash, CHUNK_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
+use crate::{
+    Finalization, Hash, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, KEY_SIZE, MAX_DEPTH, PARENT_SIZE,
+};
 use arrayref::array_ref;
 use arrayvec::ArrayVec;
 use std::cmp;
@@ -46,23 +48,51 @@ use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Decode an entire slice in the default combined mode into a bytes vector.
 /// This is a convenience wrapper around `Decoder`.
+///
+/// To decode something too large to hold in memory, stream it through a `Decoder` instead, for
+/// example with `io::copy(&mut Decoder::new(encoded_file, &hash), &mut output_file)`. That uses
+/// a small fixed buffer, and it never writes unverified bytes to the output.
 pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
-    let bytes = encoded.as_ref();
+    decode_inner(encoded.as_ref(), hash, None)
+}
+
+/// Like `decode`, but for an encoding made with `encode::encode_keyed` or
+/// `Encoder::new_keyed`. This is a convenience wrapper around `Decoder::new_keyed`.
+pub fn decode_keyed(
+    encoded: impl AsRef<[u8]>,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+) -> io::Result<Vec<u8>> {
+    decode_inner(encoded.as_ref(), hash, Some(key))
+}
+
+fn decode_inner(bytes: &[u8], hash: &Hash, key: Option<&[u8; KEY_SIZE]>) -> io::Result<Vec<u8>> {
     if bytes.len() < HEADER_SIZE {
-        return Err(Error::Truncated.into());
+        let needed = (HEADER_SIZE - bytes.len()) as u64;
+        return Err(Error::Truncated { needed }.into());
     }
     let content_len = crate::decode_len(array_ref!(bytes, 0, HEADER_SIZE));
     // Sanity check the length before making a potentially large allocation.
-    if (bytes.len() as u128) < encode::encoded_size(content_len) {
-        return Err(Error::Truncated.into());
+    let encoded_size = encode::encoded_size(content_len);
+    if (bytes.len() as u128) < encoded_size {
+        let needed = cmp::min(encoded_size - bytes.len() as u128, u64::MAX as u128) as u64;
+        return Err(Error::Truncated { needed }.into());
     }
     // There's no way to avoid zeroing this vector without unsafe code, because
     // Decoder::initializer is the default (safe) zeroing implementation anyway.
     let mut vec = vec![0; content_len as usize];
-    let mut reader = Decoder::new(bytes, hash);
+    let mut reader = match key {
+        Some(key) => Decoder::new_keyed(bytes, hash, key),
+        None => Decoder::new(bytes, hash),
+    };
     reader.read_exact(&mut vec)?;
     // One more read to confirm EOF. This is redundant in most cases, but in
     // the empty encoding case read_exact won't do any reads at all, and the Ok
@@ -74,26 +104,133 @@ pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
     Ok(vec)
 }
 
+/// Decode a combined encoding in place, replacing the contents of `buf` with the verified content.
+/// Like `decode`, but without allocating a second buffer. If decoding fails, `buf` is left empty,
+/// so that unverified bytes are never left behind.
+pub fn decode_in_place(buf: &mut Vec<u8>, hash: &Hash) -> io::Result<()> {
+    decode_in_place_inner(buf, hash, None)
+}
+
+/// Like `decode_in_place`, but for an encoding made with `encode::encode_keyed` or
+/// `Encoder::new_keyed`.
+pub fn decode_in_place_keyed(
+    buf: &mut Vec<u8>,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+) -> io::Result<()> {
+    decode_in_place_inner(buf, hash, Some(key))
+}
+
+fn decode_in_place_inner(
+    buf: &mut Vec<u8>,
+    hash: &Hash,
+    key: Option<&[u8; KEY_SIZE]>,
+) -> io::Result<()> {
+    if buf.len() < HEADER_SIZE {
+        let needed = (HEADER_SIZE - buf.len()) as u64;
+        buf.clear();
+        return Err(Error::Truncated { needed }.into());
+    }
+    // Each chunk's position in the content is behind its position in the encoding, by at least the
+    // size of the header, so writing decoded bytes never clobbers anything the decoder still needs
+    // to read. The decoder owns the buffer while it runs, and drops it if there's an error.
+    let input = io::Cursor::new(mem::take(buf));
+    let mut decoder = match key {
+        Some(key) => Decoder::new_keyed(input, hash, key),
+        None => Decoder::new(input, hash),
+    };
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut content_len = 0;
+    loop {
+        let n = decoder.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        decoder.shared.input.get_mut()[content_len..][..n].copy_from_slice(&chunk[..n]);
+        content_len += n;
+    }
+    *buf = decoder.into_inner().0.into_inner();
+    buf.truncate(content_len);
+    Ok(())
+}
+
+/// Compute the root hash of an encoding from its header and its root parent node, without reading
+/// any content. This works for both combined and outboard encodings, which start with the same 72
+/// bytes.
+///
+/// Note that this doesn't verify anything. It's a way to cheaply re-derive the hash of an
+/// encoding you already trust, for example to audit the address of a stored blob. It returns
+/// `None` if the content is a single chunk or less, because then there are no parent nodes, and
+/// the root hash depends on the content bytes.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0; 1_000_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+/// assert_eq!(Some(hash), bao::decode::root_hash(&*outboard)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn root_hash(encoding: impl Read) -> io::Result<Option<Hash>> {
+    root_hash_inner(encoding, None)
+}
+
+/// Like `root_hash`, but for an encoding made with one of the keyed `Encoder` constructors.
+pub fn root_hash_keyed(encoding: impl Read, key: &[u8; KEY_SIZE]) -> io::Result<Option<Hash>> {
+    root_hash_inner(encoding, Some(key))
+}
+
+fn root_hash_inner(
+    mut encoding: impl Read,
+    key: Option<&[u8; KEY_SIZE]>,
+) -> io::Result<Option<Hash>> {
+    let mut header = [0; HEADER_SIZE];
+    read_node(&mut encoding, &mut header)?;
+    if crate::decode_len(&header) <= CHUNK_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut parent = [0; PARENT_SIZE];
+    read_node(&mut encoding, &mut parent)?;
+    let left_child: Hash = (*array_ref!(parent, 0, 32)).into();
+    let right_child: Hash = (*array_ref!(parent, 32, 32)).into();
+    Ok(Some(crate::parent_cv(
+        &left_child,
+        &right_child,
+        Finalization::Root,
+        key,
+    )))
+}
+
 // This incremental verifier layers on top of encode::ParseState, and supports
 // both the Decoder and the SliceDecoder.
 #[derive(Clone)]
 struct VerifyState {
-    stack: ArrayVec<Hash, MAX_DEPTH>,
+    // The root hash, plus one more subtree hash for each parent node along the current path. A
+    // tree can be MAX_DEPTH parents deep, for content lengths above 2^63.
+    stack: ArrayVec<Hash, { MAX_DEPTH + 1 }>,
     parser: encode::ParseState,
     root_hash: Hash,
+    key: Option<[u8; KEY_SIZE]>,
 }
 
 impl VerifyState {
-    fn new(hash: &Hash) -> Self {
+    fn new(hash: &Hash, key: Option<&[u8; KEY_SIZE]>) -> Self {
         let mut stack = ArrayVec::new();
         stack.push(*hash);
         Self {
             stack,
             parser: encode::ParseState::new(),
             root_hash: *hash,
+            key: key.copied(),
         }
     }
 
+    fn key(&self) -> Option<&[u8; KEY_SIZE]> {
+        self.key.as_ref()
+    }
+
     fn content_position(&self) -> u64 {
         self.parser.content_position()
     }
@@ -128,20 +265,28 @@ impl VerifyState {
         self.parser.feed_header(header);
     }
 
+    fn hash_mismatch(&self, node_kind: NodeKind) -> Error {
+        let position = self.content_position();
+        Error::HashMismatch {
+            content_offset: position - position % CHUNK_SIZE as u64,
+            node_kind,
+        }
+    }
+
     fn feed_parent(&mut self, parent: &crate::ParentNode) -> Result<(), Error> {
         let finalization = self.parser.finalization();
         let expected_hash: &Hash = self.stack.last().expect("unexpectedly empty stack");
         let left_child: Hash = (*array_ref!(parent, 0, 32)).into();
         let right_child: Hash = (*array_ref!(parent, 32, 32)).into();
         let computed_hash: Hash =
-            blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+            crate::parent_cv(&left_child, &right_child, finalization, self.key());
         // Hash implements constant time equality.
         if expected_hash != &computed_hash {
-            return Err(Error::HashMismatch);
+            return Err(self.hash_mismatch(NodeKind::Parent));
         }
         self.stack.pop();
-        self.stack.push(right_child.into());
-        self.stack.push(left_child.into());
+        self.stack.push(right_child);
+        self.stack.push(left_child);
         self.parser.advance_parent();
         Ok(())
     }
@@ -150,7 +295,7 @@ impl VerifyState {
         let expected_hash = self.stack.last().expect("unexpectedly empty stack");
         // Hash implements constant time equality.
         if chunk_hash != expected_hash {
-            return Err(Error::HashMismatch);
+            return Err(self.hash_mismatch(NodeKind::Chunk));
         }
         self.stack.pop();
         self.parser.advance_chunk();
@@ -158,8 +303,8 @@ impl VerifyState {
     }
 }
 
-// It's important to manually implement Debug for VerifyState, because it holds hashes that
-// might be secret, and it would be bad to leak them to some debug log somewhere.
+// It's important to manually implement Debug for VerifyState, because it holds hashes (and maybe
+// a key) that might be secret, and it would be bad to leak them to some debug log somewhere.
 impl fmt::Debug for VerifyState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -176,18 +321,54 @@ impl fmt::Debug for VerifyState {
 /// Two errors are possible when decoding, apart from the usual IO issues: the content bytes might
 /// not have the right hash, or the encoding might not be as long as it's supposed to be. In
 /// `std::io::Read` interfaces where we have to return `std::io::Error`, these variants are
-/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively.
+/// converted to `ErrorKind::InvalidData` and `ErrorKind::UnexpectedEof` respectively. The
+/// original `Error` is kept inside the `io::Error`, and callers can get it back with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<bao::decode::Error>())`.
+///
+/// Note that a damaged length header usually shows up as a `HashMismatch`, because the length is
+/// part of the root hash. Other IO errors from the underlying readers are returned as they are,
+/// rather than wrapped in this type.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Error {
-    HashMismatch,
-    Truncated,
+    HashMismatch {
+        /// The content offset where verification failed, rounded down to the start of its chunk.
+        /// For a parent node, that chunk is somewhere beneath it.
+        content_offset: u64,
+        /// Whether the node that failed was a chunk or a parent node.
+        node_kind: NodeKind,
+    },
+    Truncated {
+        /// At least this many more bytes were needed. The all-at-once functions check the size of
+        /// the whole encoding up front, and report exactly how many bytes are missing. Incremental
+        /// decoders report what was missing from the header, parent node, or chunk they were
+        /// reading, and more may be missing after it.
+        needed: u64,
+    },
+}
+
+/// The two kinds of node in the tree, used in `Error::HashMismatch` and `DamagedNode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Parent,
+    Chunk,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::HashMismatch => write!(f, "hash mismatch"),
-            Error::Truncated => write!(f, "truncated encoding"),
+            Error::HashMismatch {
+                content_offset,
+                node_kind,
+            } => {
+                let node = match node_kind {
+                    NodeKind::Parent => "parent node",
+                    NodeKind::Chunk => "chunk",
+                };
+                write!(f, "hash mismatch in {} at offset {}", node, content_offset)
+            }
+            Error::Truncated { needed } => {
+                write!(f, "truncated encoding, needed {} more bytes", needed)
+            }
         }
     }
 }
@@ -197,12 +378,30 @@ impl error::Error for Error {}
 impl From<Error> for io::Error {
     fn from(e: Error) -> io::Error {
         match e {
-            Error::HashMismatch => io::Error::new(io::ErrorKind::InvalidData, "hash mismatch"),
-            Error::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated encoding"),
+            Error::HashMismatch { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            Error::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
         }
     }
 }
 
+// Like read_exact, but running out of input returns Error::Truncated, with the number of bytes
+// that were missing.
+fn read_node<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => {
+                let needed = (buf.len() - filled) as u64;
+                return Err(Error::Truncated { needed }.into());
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
 // Shared between Decoder and SliceDecoder.
 #[derive(Clone)]
 struct DecoderShared<T: Read, O: Read> {
@@ -215,11 +414,11 @@ struct DecoderShared<T: Read, O: Read> {
 }
 
 impl<T: Read, O: Read> DecoderShared<T, O> {
-    fn new(input: T, outboard: Option<O>, hash: &Hash) -> Self {
+    fn new(input: T, outboard: Option<O>, hash: &Hash, key: Option<&[u8; KEY_SIZE]>) -> Self {
         Self {
             input,
             outboard,
-            state: VerifyState::new(hash),
+            state: VerifyState::new(hash, key),
             buf: [0; CHUNK_SIZE],
             buf_start: 0,
             buf_end: 0,
@@ -253,9 +452,9 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         debug_assert_eq!(0, self.buf_len());
         let mut header = [0; HEADER_SIZE];
         if let Some(outboard) = &mut self.outboard {
-            outboard.read_exact(&mut header)?;
+            read_node(outboard, &mut header)?;
         } else {
-            self.input.read_exact(&mut header)?;
+            read_node(&mut self.input, &mut header)?;
         }
         self.state.feed_header(&header);
         Ok(())
@@ -265,9 +464,9 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         debug_assert_eq!(0, self.buf_len());
         let mut parent = [0; PARENT_SIZE];
         if let Some(outboard) = &mut self.outboard {
-            outboard.read_exact(&mut parent)?;
+            read_node(outboard, &mut parent)?;
         } else {
-            self.input.read_exact(&mut parent)?;
+            read_node(&mut self.input, &mut parent)?;
         }
         Ok(parent)
     }
@@ -296,10 +495,8 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
             self.get_and_feed_parent()?;
         }
         let buf_slice = &mut self.buf[..size];
-        self.input.read_exact(buf_slice)?;
-        let hash = blake3::guts::ChunkState::new(index)
-            .update(buf_slice)
-            .finalize(finalization.is_root());
+        read_node(&mut self.input, buf_slice)?;
+        let hash = crate::hash_chunk(index, buf_slice, finalization, self.state.key());
         self.state.feed_chunk(&hash)?;
         self.buf_start = skip;
         self.buf_end = size;
@@ -354,14 +551,13 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
                     };
 
                     // Read the unverified chunk.
-                    self.input.read_exact(read_buf)?;
+                    read_node(&mut self.input, read_buf)?;
 
                     // Hash it and push its hash into the VerifyState. This
                     // returns an error if the hash is bad. Otherwise, the
                     // chunk is verifiied.
-                    let chunk_hash = blake3::guts::ChunkState::new(index)
-                        .update(read_buf)
-                        .finalize(finalization.is_root());
+                    let chunk_hash =
+                        crate::hash_chunk(index, read_buf, finalization, self.state.key());
                     self.state.feed_chunk(&chunk_hash)?;
 
                     // If the output buffer was large enough for direct output,
@@ -379,6 +575,37 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
         }
     }
 
+    // Unlike read, this always verifies into the internal buffer, so that the caller can borrow
+    // the verified bytes. An empty return means EOF.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.buf_len() == 0 {
+            match self.state.read_next() {
+                NextRead::Done => break,
+                NextRead::Header => self.get_and_feed_header()?,
+                NextRead::Parent => self.get_and_feed_parent()?,
+                NextRead::Chunk {
+                    size,
+                    finalization,
+                    skip,
+                    index,
+                } => {
+                    self.buffer_verified_chunk(
+                        size,
+                        finalization,
+                        skip,
+                        index,
+                        0, /* parents_to_read */
+                    )?;
+                }
+            }
+        }
+        Ok(&self.buf[self.buf_start..self.buf_end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf_start = cmp::min(self.buf_start + amt, self.buf_end);
+    }
+
     // Returns Ok(true) to indicate the seek is finished. Note that both the
     // Decoder and the SliceDecoder will use this method (which doesn't depend on
     // io::Seek), but only the Decoder will call handle_seek_bookkeeping first.
@@ -412,6 +639,21 @@ impl<T: Read, O: Read> DecoderShared<T, O> {
 }
 
 impl<T: Read + Seek, O: Read + Seek> DecoderShared<T, O> {
+    // The length in the header isn't trusted until the final chunk has been verified, and
+    // getting there is a seek loop of its own. This leaves the position wherever that loop ended.
+    fn verified_len(&mut self) -> io::Result<u64> {
+        loop {
+            match self.state.len_next() {
+                encode::LenNext::Seek(bookkeeping) => {
+                    let next_read = self.handle_seek_bookkeeping(bookkeeping)?;
+                    let done = self.handle_seek_read(next_read)?;
+                    debug_assert!(!done);
+                }
+                encode::LenNext::Len(len) => return Ok(len),
+            }
+        }
+    }
+
     // The Decoder will call this as part of seeking, but note that the
     // SliceDecoder won't, because all the seek bookkeeping has already been
     // taken care of during slice extraction.
@@ -458,12 +700,19 @@ impl<T: Read, O: Read> fmt::Debug for DecoderShared<T, O> {
 /// [`Encoder`](../encode/struct.Encoder.html).
 ///
 /// `Decoder` supports both the combined and outboard encoding format,
-/// depending on which constructor you use.
+/// depending on which constructor you use. Encodings made with one of the
+/// keyed `Encoder` constructors need the matching keyed `Decoder`
+/// constructor, with the same key.
 ///
 /// `Decoder` supports
 /// [`std::io::Seek`](https://doc.rust-lang.org/std/io/trait.Seek.html) if the
 /// underlying reader does, but it's also compatible with non-seekable readers.
 ///
+/// To decode a memory-mapped file, wrap the map in a `std::io::Cursor`. Seeks and reads then
+/// verify only the parts of the tree they touch, without any read syscalls. Each chunk is copied
+/// out of the map before it's hashed, so if another process modifies the file during decoding,
+/// the result is at worst a spurious error, never unverified output.
+///
 /// # Example
 ///
 /// ```
@@ -498,7 +747,14 @@ pub struct Decoder<T: Read, O: Read> {
 impl<T: Read> Decoder<T, T> {
     pub fn new(inner: T, hash: &Hash) -> Self {
         Self {
-            shared: DecoderShared::new(inner, None, hash),
+            shared: DecoderShared::new(inner, None, hash, None),
+        }
+    }
+
+    /// Like `new`, but for an encoding made with `Encoder::new_keyed`.
+    pub fn new_keyed(inner: T, hash: &Hash, key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            shared: DecoderShared::new(inner, None, hash, Some(key)),
         }
     }
 }
@@ -506,7 +762,14 @@ impl<T: Read> Decoder<T, T> {
 impl<T: Read, O: Read> Decoder<T, O> {
     pub fn new_outboard(inner: T, outboard: O, hash: &Hash) -> Self {
         Self {
-            shared: DecoderShared::new(inner, Some(outboard), hash),
+            shared: DecoderShared::new(inner, Some(outboard), hash, None),
+        }
+    }
+
+    /// Like `new_outboard`, but for an encoding made with `Encoder::new_outboard_keyed`.
+    pub fn new_outboard_keyed(inner: T, outboard: O, hash: &Hash, key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            shared: DecoderShared::new(inner, Some(outboard), hash, Some(key)),
         }
     }
 
@@ -515,6 +778,38 @@ impl<T: Read, O: Read> Decoder<T, O> {
     pub fn into_inner(self) -> (T, Option<O>) {
         (self.shared.input, self.shared.outboard)
     }
+
+    /// Verify the next chunk and return its content offset along with a reference to its bytes,
+    /// or `None` at EOF. The bytes are borrowed from the `Decoder`'s internal buffer, so there's
+    /// no copy into a caller buffer. If a previous `read` or seek stopped partway through a
+    /// chunk, this returns the rest of that chunk.
+    ///
+    /// This can't be an `Iterator`, because each chunk borrows from the `Decoder`, so loop over
+    /// it with `while let`:
+    ///
+    /// ```
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let input = vec![0xff; 5000];
+    /// let (encoded, hash) = bao::encode::encode(&input);
+    /// let mut decoder = bao::decode::Decoder::new(&*encoded, &hash);
+    /// let mut total = 0;
+    /// while let Some((offset, chunk)) = decoder.next_chunk()? {
+    ///     assert_eq!(total, offset);
+    ///     total += chunk.len() as u64;
+    /// }
+    /// assert_eq!(input.len() as u64, total);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn next_chunk(&mut self) -> io::Result<Option<(u64, &[u8])>> {
+        if self.shared.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let offset = self.shared.adjusted_content_position();
+        let (start, end) = (self.shared.buf_start, self.shared.buf_end);
+        self.shared.consume(end - start);
+        Ok(Some((offset, &self.shared.buf[start..end])))
+    }
 }
 
 impl<T: Read, O: Read> Read for Decoder<T, O> {
@@ -523,6 +818,39 @@ impl<T: Read, O: Read> Read for Decoder<T, O> {
     }
 }
 
+/// `fill_buf` verifies at most one chunk at a time and returns the verified bytes from the
+/// `Decoder`'s internal buffer.
+impl<T: Read, O: Read> BufRead for Decoder<T, O> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.shared.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.shared.consume(amt)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Decoder<T, O> {
+    /// Return the content length, verified against the root hash. This reads the header and the
+    /// parent nodes and final chunk along the right edge of the tree, but none of the rest of the
+    /// content, so it's cheap even for very large encodings. The read position is preserved,
+    /// whether or not the length verifies.
+    ///
+    /// The length in the header can't be trusted by itself. It's only authenticated by the
+    /// final chunk, so this returns an error if any node on that path is corrupt.
+    pub fn content_len(&mut self) -> io::Result<u64> {
+        let position = self.shared.adjusted_content_position();
+        self.shared.clear_buf();
+        let len_result = self.shared.verified_len();
+        // Restore the position even if the length failed to verify. If that fails too, the
+        // length error is the more useful one to return.
+        let seek_result = self.seek(SeekFrom::Start(position));
+        let len = len_result?;
+        seek_result?;
+        Ok(len)
+    }
+}
+
 impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         // Clear the internal buffer when seeking. The buffered bytes won't be
@@ -537,17 +865,7 @@ impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
             SeekFrom::End(offset) => {
                 // To seek from the end we have to get the length, and that may
                 // require as a seek loop of its own to verify the length.
-                let content_len = loop {
-                    match self.shared.state.len_next() {
-                        encode::LenNext::Seek(bookkeeping) => {
-                            let next_read = self.shared.handle_seek_bookkeeping(bookkeeping)?;
-                            let done = self.shared.handle_seek_read(next_read)?;
-                            debug_assert!(!done);
-                        }
-                        encode::LenNext::Len(len) => break len,
-                    }
-                };
-                add_offset(content_len, offset)?
+                add_offset(self.shared.verified_len()?, offset)?
             }
             SeekFrom::Current(offset) => {
                 add_offset(self.shared.adjusted_content_position(), offset)?
@@ -574,7 +892,7 @@ fn add_offset(position: u64, offset: i64) -> io::Result<u64> {
             io::ErrorKind::InvalidInput,
             "seek before beginning",
         ))
-    } else if sum > u64::max_value() as i128 {
+    } else if sum > u64::MAX as i128 {
         Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             "seek target overflowed u64",
@@ -641,8 +959,30 @@ pub struct SliceDecoder<T: Read> {
 
 impl<T: Read> SliceDecoder<T> {
     pub fn new(inner: T, hash: &Hash, slice_start: u64, slice_len: u64) -> Self {
+        Self::new_inner(inner, hash, None, slice_start, slice_len)
+    }
+
+    /// Like `new`, but for a slice of a keyed encoding. Note that extracting a slice doesn't
+    /// require the key, only decoding it does.
+    pub fn new_keyed(
+        inner: T,
+        hash: &Hash,
+        key: &[u8; KEY_SIZE],
+        slice_start: u64,
+        slice_len: u64,
+    ) -> Self {
+        Self::new_inner(inner, hash, Some(key), slice_start, slice_len)
+    }
+
+    fn new_inner(
+        inner: T,
+        hash: &Hash,
+        key: Option<&[u8; KEY_SIZE]>,
+        slice_start: u64,
+        slice_len: u64,
+    ) -> Self {
         Self {
-            shared: DecoderShared::new(inner, None, hash),
+            shared: DecoderShared::new(inner, None, hash, key),
             slice_start,
             slice_remaining: slice_len,
             need_fake_read: slice_len == 0,
@@ -695,6 +1035,623 @@ impl<T: Read> Read for SliceDecoder<T> {
     }
 }
 
+/// The results of `verify_sample`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SampleReport {
+    /// The length of the content. This is only verified if the final chunk verified. Otherwise
+    /// it's the length from the header, and the final chunk is in `corrupt_chunks`.
+    pub content_len: u64,
+    /// The total number of chunks in the content.
+    pub total_chunks: u64,
+    /// The number of chunks checked, counting repeats, and counting the final chunk if it failed
+    /// while verifying the length.
+    pub chunks_sampled: u64,
+    /// The indexes of the sampled chunks that failed verification, in the order they were
+    /// sampled. Note that a corrupt parent node fails every chunk beneath it.
+    pub corrupt_chunks: Vec<u64>,
+}
+
+impl SampleReport {
+    /// Returns true if every sampled chunk verified.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_chunks.is_empty()
+    }
+
+    /// The probability that this many samples would have caught at least one corrupt chunk, if
+    /// the given fraction of chunks were corrupt. For example, 300 samples detect 1% corruption
+    /// with about 95% probability, regardless of the size of the content.
+    pub fn detection_probability(&self, corrupt_fraction: f64) -> f64 {
+        1.0 - (1.0 - corrupt_fraction).powf(self.chunks_sampled as f64)
+    }
+}
+
+/// Verify a random sample of chunks against an outboard encoding, rather than the whole content.
+///
+/// This first verifies the content length, which means checking the final chunk. Then it calls
+/// `pick` `samples` times, passing the total number of chunks, and verifies each chunk index that
+/// `pick` returns, along with the parent nodes above it. Hash mismatches and truncated inputs are
+/// recorded in the report, and other IO errors are returned. If the final chunk or a parent node
+/// above it is damaged, the final chunk is recorded as a corrupt sample, and sampling continues
+/// with the unverified length from the header. A missing header is returned as an error.
+///
+/// The random number generator is up to the caller. If the storage being audited could be
+/// adversarial, the sampled indexes must be unpredictable to it.
+///
+/// # Panics
+///
+/// This panics if `pick` returns an index greater than or equal to the total number of chunks.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0; 1_000_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+///
+/// // A real caller would use a proper random number generator here.
+/// let mut counter = 0;
+/// let report = bao::decode::verify_sample(
+///     std::io::Cursor::new(&input),
+///     std::io::Cursor::new(&outboard),
+///     &hash,
+///     100,
+///     |total_chunks| {
+///         counter += 7919;
+///         counter % total_chunks
+///     },
+/// )?;
+/// assert!(report.is_ok());
+/// assert!(report.detection_probability(0.05) > 0.99);
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify_sample<T: Read + Seek, O: Read + Seek>(
+    content: T,
+    outboard: O,
+    hash: &Hash,
+    samples: u64,
+    pick: impl FnMut(u64) -> u64,
+) -> io::Result<SampleReport> {
+    verify_sample_inner(content, outboard, hash, None, samples, pick)
+}
+
+/// Like `verify_sample`, but for an outboard encoding made with `Encoder::new_outboard_keyed`.
+/// With the wrong key, the final chunk and every sampled chunk are reported as corrupt.
+pub fn verify_sample_keyed<T: Read + Seek, O: Read + Seek>(
+    content: T,
+    outboard: O,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+    samples: u64,
+    pick: impl FnMut(u64) -> u64,
+) -> io::Result<SampleReport> {
+    verify_sample_inner(content, outboard, hash, Some(key), samples, pick)
+}
+
+fn verify_sample_inner<T: Read + Seek, O: Read + Seek>(
+    content: T,
+    mut outboard: O,
+    hash: &Hash,
+    key: Option<&[u8; KEY_SIZE]>,
+    samples: u64,
+    mut pick: impl FnMut(u64) -> u64,
+) -> io::Result<SampleReport> {
+    let mut header = [0; HEADER_SIZE];
+    outboard.seek(SeekFrom::Start(0))?;
+    read_node(&mut outboard, &mut header)?;
+    outboard.seek(SeekFrom::Start(0))?;
+    let mut decoder = Decoder {
+        shared: DecoderShared::new(content, Some(outboard), hash, key),
+    };
+    let mut corrupt_chunks = Vec::new();
+    let mut chunks_sampled = samples;
+    let content_len = match decoder.seek(SeekFrom::End(0)) {
+        Ok(len) => len,
+        Err(e)
+            if e.kind() == io::ErrorKind::InvalidData
+                || e.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            let header_len = crate::decode_len(&header);
+            corrupt_chunks.push(encode::count_chunks(header_len) - 1);
+            chunks_sampled += 1;
+            header_len
+        }
+        Err(e) => return Err(e),
+    };
+    let total_chunks = encode::count_chunks(content_len);
+    for _ in 0..samples {
+        let index = pick(total_chunks);
+        assert!(index < total_chunks, "chunk index out of range");
+        let result = decoder
+            .seek(SeekFrom::Start(index * CHUNK_SIZE as u64))
+            .and_then(|_| io::copy(&mut (&mut decoder).take(CHUNK_SIZE as u64), &mut io::sink()));
+        match result {
+            Ok(_) => {}
+            Err(e)
+                if e.kind() == io::ErrorKind::InvalidData
+                    || e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                corrupt_chunks.push(index)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(SampleReport {
+        content_len,
+        total_chunks,
+        chunks_sampled,
+        corrupt_chunks,
+    })
+}
+
+/// A node that failed verification in `verify_encoding` or `verify_outboard`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamagedNode {
+    /// The offset of the node. For chunks of an outboard encoding, this is an offset in the
+    /// content. Otherwise it's an offset in the encoding.
+    pub offset: u64,
+    /// The range of content beneath the node, none of which can be verified.
+    pub content_range: Range<u64>,
+    /// Whether the node is a chunk or a parent node.
+    pub node_kind: NodeKind,
+}
+
+/// Check every node of a combined encoding, and return the ones that don't match, instead of
+/// stopping at the first error like `Decoder` does.
+///
+/// The tree is walked from the root. Nodes beneath a damaged parent can't be checked, so each
+/// damaged node covers its whole subtree, and the returned nodes don't overlap. A node that's cut
+/// off by the end of the file counts as damaged. If the length header itself is damaged, the root
+/// won't match, and everything is reported as a single damaged node. An empty result means the
+/// whole encoding verified. Only IO errors are returned as errors.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0; 100_000];
+/// let (mut encoded, hash) = bao::encode::encode(&input);
+/// let last_index = encoded.len() - 1;
+/// encoded[last_index] ^= 1;
+/// let damaged = bao::decode::verify_encoding(std::io::Cursor::new(&encoded), &hash)?;
+/// assert_eq!(1, damaged.len());
+/// assert_eq!(bao::decode::NodeKind::Chunk, damaged[0].node_kind);
+/// assert_eq!(99_328..100_000, damaged[0].content_range);
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify_encoding(encoded: impl Read + Seek, hash: &Hash) -> io::Result<Vec<DamagedNode>> {
+    DamageScanner::new(encoded, None::<io::Empty>, None).scan(hash)
+}
+
+/// Like `verify_encoding`, but for an encoding made with `Encoder::new_keyed`. With the wrong
+/// key, the root is reported as damaged.
+pub fn verify_encoding_keyed(
+    encoded: impl Read + Seek,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+) -> io::Result<Vec<DamagedNode>> {
+    DamageScanner::new(encoded, None::<io::Empty>, Some(key)).scan(hash)
+}
+
+/// Like `verify_encoding`, but for content and a separate outboard encoding.
+pub fn verify_outboard(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    hash: &Hash,
+) -> io::Result<Vec<DamagedNode>> {
+    DamageScanner::new(content, Some(outboard), None).scan(hash)
+}
+
+/// Like `verify_outboard`, but for an outboard encoding made with `Encoder::new_outboard_keyed`.
+pub fn verify_outboard_keyed(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+) -> io::Result<Vec<DamagedNode>> {
+    DamageScanner::new(content, Some(outboard), Some(key)).scan(hash)
+}
+
+/// Recover everything that still verifies from a damaged combined encoding.
+///
+/// This walks the tree like `verify_encoding`, but instead of reporting the damage, it writes each
+/// verified chunk to `output` at its content offset and returns the content ranges that were
+/// written, in order and with adjacent ranges merged. Damaged ranges are skipped, so whatever was
+/// in `output` there before is left alone, and `output` isn't extended past the last verified
+/// chunk. If the length header is damaged, nothing verifies and the result is empty. Only IO
+/// errors are returned as errors.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let input = vec![0xff; 100_000];
+/// let (mut encoded, hash) = bao::encode::encode(&input);
+/// let last_index = encoded.len() - 1;
+/// encoded[last_index] ^= 1;
+/// let mut output = Cursor::new(Vec::new());
+/// let verified = bao::decode::salvage(Cursor::new(&encoded), &hash, &mut output)?;
+/// assert_eq!(vec![0..99_328], verified);
+/// assert_eq!(&input[..99_328], &output.get_ref()[..]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn salvage(
+    encoded: impl Read + Seek,
+    hash: &Hash,
+    output: impl Write + Seek,
+) -> io::Result<Vec<Range<u64>>> {
+    salvage_inner(encoded, None::<io::Empty>, hash, None, output)
+}
+
+/// Like `salvage`, but for an encoding made with `Encoder::new_keyed`.
+pub fn salvage_keyed(
+    encoded: impl Read + Seek,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+    output: impl Write + Seek,
+) -> io::Result<Vec<Range<u64>>> {
+    salvage_inner(encoded, None::<io::Empty>, hash, Some(key), output)
+}
+
+/// Like `salvage`, but for content and a separate outboard encoding. The verified parts of
+/// `content` are copied to `output`. If the outboard itself is damaged, the chunks beneath the
+/// damaged parent nodes can't be verified, even if the content there is intact.
+pub fn salvage_outboard(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    hash: &Hash,
+    output: impl Write + Seek,
+) -> io::Result<Vec<Range<u64>>> {
+    salvage_inner(content, Some(outboard), hash, None, output)
+}
+
+/// Like `salvage_outboard`, but for an outboard encoding made with `Encoder::new_outboard_keyed`.
+pub fn salvage_outboard_keyed(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    hash: &Hash,
+    key: &[u8; KEY_SIZE],
+    output: impl Write + Seek,
+) -> io::Result<Vec<Range<u64>>> {
+    salvage_inner(content, Some(outboard), hash, Some(key), output)
+}
+
+fn salvage_inner(
+    input: impl Read + Seek,
+    outboard: Option<impl Read + Seek>,
+    hash: &Hash,
+    key: Option<&[u8; KEY_SIZE]>,
+    mut output: impl Write + Seek,
+) -> io::Result<Vec<Range<u64>>> {
+    let mut verified: Vec<Range<u64>> = Vec::new();
+    let mut on_chunk = |start: u64, chunk: &[u8]| -> io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        output.seek(SeekFrom::Start(start))?;
+        output.write_all(chunk)?;
+        let end = start + chunk.len() as u64;
+        match verified.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => verified.push(start..end),
+        }
+        Ok(())
+    };
+    let mut scanner = DamageScanner::new(input, outboard, key);
+    scanner.on_chunk = Some(&mut on_chunk);
+    scanner.scan(hash)?;
+    Ok(verified)
+}
+
+// Called with the content offset and bytes of each chunk that verifies.
+type ChunkCallback<'a> = &'a mut dyn FnMut(u64, &[u8]) -> io::Result<()>;
+
+struct DamageScanner<'a, T, O> {
+    input: T,
+    outboard: Option<O>,
+    key: Option<[u8; KEY_SIZE]>,
+    damaged: Vec<DamagedNode>,
+    on_chunk: Option<ChunkCallback<'a>>,
+}
+
+impl<'a, T: Read + Seek, O: Read + Seek> DamageScanner<'a, T, O> {
+    fn new(input: T, outboard: Option<O>, key: Option<&[u8; KEY_SIZE]>) -> Self {
+        Self {
+            input,
+            outboard,
+            key: key.copied(),
+            damaged: Vec::new(),
+            on_chunk: None,
+        }
+    }
+
+    fn scan(mut self, hash: &Hash) -> io::Result<Vec<DamagedNode>> {
+        let mut header = [0; HEADER_SIZE];
+        let header_reader: &mut dyn Read = match &mut self.outboard {
+            Some(outboard) => {
+                outboard.seek(SeekFrom::Start(0))?;
+                outboard
+            }
+            None => {
+                self.input.seek(SeekFrom::Start(0))?;
+                &mut self.input
+            }
+        };
+        read_node(header_reader, &mut header)?;
+        let content_len = crate::decode_len(&header);
+        self.subtree(
+            0,
+            content_len,
+            HEADER_SIZE as u128,
+            hash,
+            Finalization::Root,
+        )?;
+        Ok(self.damaged)
+    }
+
+    fn subtree(
+        &mut self,
+        start: u64,
+        len: u64,
+        encoding_position: u128,
+        expected: &Hash,
+        finalization: Finalization,
+    ) -> io::Result<()> {
+        let key = self.key;
+        let is_chunk = len <= CHUNK_SIZE as u64;
+        let offset = if is_chunk && self.outboard.is_some() {
+            start
+        } else {
+            encode::cast_offset(encoding_position)?
+        };
+        let mut buf = [0; CHUNK_SIZE];
+        let node = if is_chunk {
+            &mut buf[..len as usize]
+        } else {
+            &mut buf[..PARENT_SIZE]
+        };
+        let reader: &mut dyn Read = match (&mut self.outboard, is_chunk) {
+            (Some(outboard), false) => {
+                outboard.seek(SeekFrom::Start(offset))?;
+                outboard
+            }
+            _ => {
+                self.input.seek(SeekFrom::Start(offset))?;
+                &mut self.input
+            }
+        };
+        let intact = match reader.read_exact(node) {
+            Ok(()) if is_chunk => {
+                let chunk_index = start / CHUNK_SIZE as u64;
+                let intact =
+                    crate::hash_chunk(chunk_index, node, finalization, key.as_ref()) == *expected;
+                if let (true, Some(on_chunk)) = (intact, &mut self.on_chunk) {
+                    on_chunk(start, node)?;
+                }
+                intact
+            }
+            Ok(()) => {
+                let left_cv = Hash::from(*array_ref!(node, 0, HASH_SIZE));
+                let right_cv = Hash::from(*array_ref!(node, HASH_SIZE, HASH_SIZE));
+                if crate::parent_cv(&left_cv, &right_cv, finalization, key.as_ref()) == *expected {
+                    let left_len = encode::left_subtree_len(len);
+                    let left_position = encoding_position + PARENT_SIZE as u128;
+                    let right_position = left_position
+                        + if self.outboard.is_some() {
+                            encode::outboard_subtree_size(left_len)
+                        } else {
+                            encode::encoded_subtree_size(left_len)
+                        };
+                    let not_root = Finalization::NotRoot;
+                    self.subtree(start, left_len, left_position, &left_cv, not_root)?;
+                    let (right_start, right_len) = (start + left_len, len - left_len);
+                    self.subtree(right_start, right_len, right_position, &right_cv, not_root)?;
+                    return Ok(());
+                }
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        if !intact {
+            self.damaged.push(DamagedNode {
+                offset,
+                content_range: start..start + len,
+                node_kind: if is_chunk {
+                    NodeKind::Chunk
+                } else {
+                    NodeKind::Parent
+                },
+            });
+        }
+        Ok(())
+    }
+}
+
+// Scrubbing verifies this many bytes between rate limit checks.
+const SCRUB_BATCH_SIZE: u64 = 16 * CHUNK_SIZE as u64;
+
+/// A background verifier that checks content against an outboard encoding at a limited rate.
+///
+/// `Scrubber` is meant to run on its own thread alongside other IO, for example to periodically
+/// re-verify stored files. It verifies the content from start to finish, sleeping as needed to
+/// stay under the configured bytes-per-second budget. Another thread can pause and resume it with
+/// a `PauseHandle`.
+///
+/// If verification fails, `step` or `run` returns the error, and `position` reports the offset of
+/// the first byte that wasn't verified.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let input = vec![0; 100_000];
+/// let (outboard, hash) = bao::encode::outboard(&input);
+///
+/// let mut scrubber = bao::decode::Scrubber::new(Cursor::new(input), Cursor::new(outboard), &hash);
+/// scrubber.set_rate_limit(Some(10_000_000));
+/// let pause_handle = scrubber.pause_handle();
+/// let scrub_thread = std::thread::spawn(move || {
+///     scrubber.run()?;
+///     Ok::<u64, std::io::Error>(scrubber.position())
+/// });
+///
+/// // Production IO could call pause_handle.pause() and pause_handle.resume() here.
+/// # pause_handle.pause();
+/// # pause_handle.resume();
+/// assert_eq!(100_000, scrub_thread.join().unwrap()?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Scrubber<T: Read + Seek, O: Read + Seek> {
+    decoder: Decoder<T, O>,
+    bytes_per_second: Option<u64>,
+    pause_handle: PauseHandle,
+    position: u64,
+    // The rate limit applies to the bytes verified since the window started. The window restarts
+    // after a pause, so that resuming doesn't cause a burst.
+    window_start: Option<Instant>,
+    window_bytes: u64,
+}
+
+impl<T: Read + Seek, O: Read + Seek> Scrubber<T, O> {
+    /// Create a new `Scrubber` for a content file and its outboard encoding. By default there's
+    /// no rate limit.
+    pub fn new(content: T, outboard: O, hash: &Hash) -> Self {
+        Self::from_decoder(Decoder::new_outboard(content, outboard, hash))
+    }
+
+    /// Like `new`, but for an outboard encoding made with `Encoder::new_outboard_keyed`.
+    pub fn new_keyed(content: T, outboard: O, hash: &Hash, key: &[u8; KEY_SIZE]) -> Self {
+        Self::from_decoder(Decoder::new_outboard_keyed(content, outboard, hash, key))
+    }
+
+    fn from_decoder(decoder: Decoder<T, O>) -> Self {
+        Self {
+            decoder,
+            bytes_per_second: None,
+            pause_handle: PauseHandle::new(),
+            position: 0,
+            window_start: None,
+            window_bytes: 0,
+        }
+    }
+
+    /// Set the maximum number of content bytes to verify per second, or `None` for no limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the limit is `Some(0)`. Use a `PauseHandle` to stop verifying for a while.
+    pub fn set_rate_limit(&mut self, bytes_per_second: Option<u64>) {
+        assert_ne!(Some(0), bytes_per_second, "rate limit must be nonzero");
+        self.bytes_per_second = bytes_per_second;
+        self.window_start = None;
+        self.window_bytes = 0;
+    }
+
+    /// Return a handle that can pause and resume this `Scrubber` from another thread.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause_handle.clone()
+    }
+
+    /// The number of content bytes verified so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Verify the next batch of content, blocking while paused and sleeping if necessary to
+    /// respect the rate limit. Returns `Ok(false)` once all the content has been verified.
+    pub fn step(&mut self) -> io::Result<bool> {
+        if self.pause_handle.wait_while_paused() {
+            self.window_start = None;
+            self.window_bytes = 0;
+        }
+        let window_start = *self.window_start.get_or_insert_with(Instant::now);
+        let result = io::copy(
+            &mut (&mut self.decoder).take(SCRUB_BATCH_SIZE),
+            &mut io::sink(),
+        );
+        // If verification fails partway through the batch, the decoder stops at the start of the
+        // chunk that failed, so its position still counts everything verified before the error.
+        let n = self.decoder.shared.adjusted_content_position() - self.position;
+        self.position += n;
+        self.window_bytes += n;
+        result?;
+        if let Some(bytes_per_second) = self.bytes_per_second {
+            let elapsed_budget =
+                Duration::from_secs_f64(self.window_bytes as f64 / bytes_per_second as f64);
+            let now = Instant::now();
+            if window_start + elapsed_budget > now {
+                thread::sleep(window_start + elapsed_budget - now);
+            }
+        }
+        Ok(n == SCRUB_BATCH_SIZE)
+    }
+
+    /// Verify all the remaining content. This is equivalent to calling `step` until it returns
+    /// `Ok(false)`.
+    pub fn run(&mut self) -> io::Result<()> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Return the underlying content and outboard readers.
+    pub fn into_inner(self) -> (T, O) {
+        let (content, outboard) = self.decoder.into_inner();
+        (content, outboard.expect("outboard decoder"))
+    }
+}
+
+/// A handle for pausing and resuming a `Scrubber`, possibly from another thread.
+#[derive(Clone, Debug)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+    fn new() -> Self {
+        Self {
+            state: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    /// Pause the `Scrubber`. It finishes the batch it's currently verifying, if any, and then
+    /// blocks until `resume` is called.
+    pub fn pause(&self) {
+        *self.state.0.lock().unwrap() = true;
+    }
+
+    /// Resume the `Scrubber` after a `pause`.
+    pub fn resume(&self) {
+        *self.state.0.lock().unwrap() = false;
+        self.state.1.notify_all();
+    }
+
+    /// Returns true if the `Scrubber` is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    // Returns true if we had to wait.
+    fn wait_while_paused(&self) -> bool {
+        let (lock, condvar) = &*self.state;
+        let mut paused = lock.lock().unwrap();
+        let mut waited = false;
+        while *paused {
+            waited = true;
+            paused = condvar.wait(paused).unwrap();
+        }
+        waited
+    }
+}
+
 #[cfg(test)]
 pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
     // Fill the input with incrementing bytes, so that reads from different sections are very
@@ -702,14 +1659,14 @@ pub(crate) fn make_test_input(len: usize) -> Vec<u8> {
     let mut ret = Vec::new();
     let mut counter = 0u64;
     while ret.len() < len {
-        if counter < u8::max_value() as u64 {
+        if counter < u8::MAX as u64 {
             ret.push(counter as u8);
-        } else if counter < u16::max_value() as u64 {
+        } else if counter < u16::MAX as u64 {
             ret.extend_from_slice(&(counter as u16).to_be_bytes());
-        } else if counter < u32::max_value() as u64 {
+        } else if counter < u32::MAX as u64 {
             ret.extend_from_slice(&(counter as u32).to_be_bytes());
         } else {
-            ret.extend_from_slice(&(counter as u64).to_be_bytes());
+            ret.extend_from_slice(&counter.to_be_bytes());
         }
         counter += 1;
     }
@@ -740,6 +1697,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_decode_in_place() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut buf = encoded.clone();
+            decode_in_place(&mut buf, &hash).unwrap();
+            assert_eq!(input, buf);
+
+            // A corrupt encoding leaves the buffer empty.
+            let mut buf = encoded.clone();
+            *buf.last_mut().unwrap() ^= 1;
+            if case == 0 {
+                // There's no content to corrupt, so corrupt the length instead.
+                buf[0] ^= 1;
+            }
+            let err = decode_in_place(&mut buf, &hash).unwrap_err();
+            assert!(buf.is_empty());
+            assert!(
+                err.kind() == io::ErrorKind::InvalidData
+                    || err.kind() == io::ErrorKind::UnexpectedEof
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_in_place_keyed() {
+        let key = [42; KEY_SIZE];
+        let input = make_test_input(10 * CHUNK_SIZE + 1);
+        let (encoded, hash) = encode::encode_keyed(&input, &key);
+        let mut buf = encoded.clone();
+        decode_in_place_keyed(&mut buf, &hash, &key).unwrap();
+        assert_eq!(input, buf);
+
+        // Without the key, nothing verifies.
+        let mut buf = encoded.clone();
+        let err = decode_in_place(&mut buf, &hash).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_decode_outboard() {
         for &case in crate::test::TEST_CASES {
@@ -753,6 +1752,431 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_decode_keyed() {
+        let key = [42; KEY_SIZE];
+        let wrong_key = [43; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut encoder = encode::Encoder::new_keyed(Cursor::new(Vec::new()), &key);
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            let encoded = encoder.into_inner().into_inner();
+            let mut encoder = encode::Encoder::new_outboard_keyed(Cursor::new(Vec::new()), &key);
+            encoder.write_all(&input).unwrap();
+            encoder.finalize().unwrap();
+            let outboard = encoder.into_inner().into_inner();
+
+            let mut output = Vec::new();
+            let mut decoder = Decoder::new_keyed(&*encoded, &hash, &key);
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            let mut output = Vec::new();
+            let mut decoder = Decoder::new_outboard_keyed(&input[..], &*outboard, &hash, &key);
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            assert_eq!(input, decode_keyed(&encoded, &hash, &key).unwrap());
+            let err = decode_keyed(&encoded, &hash, &wrong_key).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+            // Decoding with the wrong key, or with no key at all, should fail.
+            let mut decoder = Decoder::new_keyed(&*encoded, &hash, &wrong_key);
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let err = decode(&encoded, &hash).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+            // Slices of a keyed encoding are extracted without the key.
+            let slice_start = case as u64 / 2;
+            let slice_len = CHUNK_SIZE as u64;
+            let mut slice = Vec::new();
+            encode::SliceExtractor::new(Cursor::new(&encoded), slice_start, slice_len)
+                .read_to_end(&mut slice)
+                .unwrap();
+            let mut output = Vec::new();
+            let mut decoder = SliceDecoder::new_keyed(&*slice, &hash, &key, slice_start, slice_len);
+            decoder.read_to_end(&mut output).unwrap();
+            let expected_end = cmp::min(input.len(), (slice_start + slice_len) as usize);
+            assert_eq!(&input[slice_start as usize..expected_end], &*output);
+        }
+    }
+
+    #[test]
+    fn test_error_details() {
+        fn decode_error(encoded: &[u8], hash: &Hash, seek_to: u64) -> Error {
+            let mut decoder = Decoder::new(Cursor::new(encoded), hash);
+            decoder.seek(SeekFrom::Start(seek_to)).unwrap();
+            let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            *err.get_ref().unwrap().downcast_ref::<Error>().unwrap()
+        }
+
+        // Four chunks: the root, the left parent, two chunks, the right parent, two chunks.
+        let input = make_test_input(4 * CHUNK_SIZE);
+        let (encoded, hash) = encode::encode(&input);
+        let third_chunk = HEADER_SIZE + 3 * PARENT_SIZE + 2 * CHUNK_SIZE;
+        let mut bad_chunk = encoded.clone();
+        bad_chunk[third_chunk + 10] ^= 1;
+        let expected = Error::HashMismatch {
+            content_offset: 2 * CHUNK_SIZE as u64,
+            node_kind: NodeKind::Chunk,
+        };
+        assert_eq!(expected, decode_error(&bad_chunk, &hash, 0));
+        assert_eq!(
+            expected,
+            decode_error(&bad_chunk, &hash, 2 * CHUNK_SIZE as u64 + 5)
+        );
+        assert_eq!(
+            "hash mismatch in chunk at offset 2048",
+            decode(&bad_chunk, &hash).unwrap_err().to_string(),
+        );
+
+        let mut bad_root = encoded.clone();
+        bad_root[HEADER_SIZE] ^= 1;
+        let expected = Error::HashMismatch {
+            content_offset: 0,
+            node_kind: NodeKind::Parent,
+        };
+        assert_eq!(expected, decode_error(&bad_root, &hash, 0));
+
+        let truncated_error = |err: io::Error| {
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+            *err.get_ref().unwrap().downcast_ref::<Error>().unwrap()
+        };
+        let err = decode(&encoded[..HEADER_SIZE - 1], &hash).unwrap_err();
+        assert_eq!(Error::Truncated { needed: 1 }, truncated_error(err));
+        let err = decode(&encoded[..encoded.len() - 10], &hash).unwrap_err();
+        assert_eq!(Error::Truncated { needed: 10 }, truncated_error(err));
+        let mut decoder = Decoder::new(&encoded[..encoded.len() - 10], &hash);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(Error::Truncated { needed: 10 }, truncated_error(err));
+        assert_eq!(
+            "truncated encoding, needed 10 more bytes",
+            decode(&encoded[..encoded.len() - 10], &hash)
+                .unwrap_err()
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_verify_encoding() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let check_combined = |encoded: &[u8]| verify_encoding(Cursor::new(encoded), &hash);
+            let check_outboard = |input: &[u8]| {
+                verify_outboard(Cursor::new(input), Cursor::new(&outboard), &hash).unwrap()
+            };
+            assert_eq!(Vec::<DamagedNode>::new(), check_combined(&encoded).unwrap());
+            assert_eq!(Vec::<DamagedNode>::new(), check_outboard(&input));
+            if case == 0 {
+                continue;
+            }
+
+            // Damage the first and last chunks.
+            let last_chunk_start = (case - 1) / CHUNK_SIZE * CHUNK_SIZE;
+            let last_chunk = DamagedNode {
+                offset: encoded.len() as u64 - (case - last_chunk_start) as u64,
+                content_range: last_chunk_start as u64..case as u64,
+                node_kind: NodeKind::Chunk,
+            };
+            let mut bad_encoded = encoded.clone();
+            *bad_encoded.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                vec![last_chunk.clone()],
+                check_combined(&bad_encoded).unwrap()
+            );
+            let mut bad_input = input.clone();
+            *bad_input.last_mut().unwrap() ^= 1;
+            let damaged = check_outboard(&bad_input);
+            assert_eq!(last_chunk_start as u64, damaged[0].offset);
+            assert_eq!(last_chunk.content_range, damaged[0].content_range);
+            let first_chunk_offset = HEADER_SIZE as u64
+                + PARENT_SIZE as u64 * encode::pre_order_parent_nodes(0, case as u64) as u64;
+            if last_chunk_start > 0 {
+                bad_encoded[first_chunk_offset as usize] ^= 1;
+                let damaged = check_combined(&bad_encoded).unwrap();
+                assert_eq!(2, damaged.len());
+                assert_eq!(first_chunk_offset, damaged[0].offset);
+                assert_eq!(0..CHUNK_SIZE as u64, damaged[0].content_range);
+                assert_eq!(last_chunk, damaged[1]);
+            }
+
+            // A truncated encoding damages the last chunk.
+            let truncated = &encoded[..encoded.len() - 1];
+            assert_eq!(vec![last_chunk], check_combined(truncated).unwrap());
+
+            // Damage to the root parent node hides everything beneath it.
+            if case > CHUNK_SIZE {
+                let mut bad_encoded = encoded.clone();
+                bad_encoded[HEADER_SIZE] ^= 1;
+                let root = DamagedNode {
+                    offset: HEADER_SIZE as u64,
+                    content_range: 0..case as u64,
+                    node_kind: NodeKind::Parent,
+                };
+                assert_eq!(vec![root], check_combined(&bad_encoded).unwrap());
+            }
+        }
+
+        let err =
+            verify_encoding(Cursor::new(&[0; HEADER_SIZE - 1]), &blake3::hash(b"")).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        // Other IO errors reading the header are passed through.
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+            }
+        }
+        impl Seek for FailingReader {
+            fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+                Ok(0)
+            }
+        }
+        let err = verify_encoding(FailingReader, &blake3::hash(b"")).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    }
+
+    #[test]
+    fn test_verify_keyed() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode_keyed(&input, &key);
+            let (outboard, _) = encode::outboard_keyed(&input, &key);
+            let damaged = verify_encoding_keyed(Cursor::new(&encoded), &hash, &key).unwrap();
+            assert_eq!(Vec::<DamagedNode>::new(), damaged);
+            let damaged =
+                verify_outboard_keyed(Cursor::new(&input), Cursor::new(&outboard), &hash, &key)
+                    .unwrap();
+            assert_eq!(Vec::<DamagedNode>::new(), damaged);
+
+            // Without the key, the root doesn't match.
+            let damaged = verify_encoding(Cursor::new(&encoded), &hash).unwrap();
+            assert_eq!(1, damaged.len());
+            assert_eq!(0..case as u64, damaged[0].content_range);
+        }
+    }
+
+    #[test]
+    fn test_salvage() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let run = |encoded: &[u8]| {
+                let mut output = Cursor::new(vec![0xaa; case]);
+                let verified = salvage(Cursor::new(encoded), &hash, &mut output).unwrap();
+                (verified, output.into_inner())
+            };
+            let (verified, output) = run(&encoded);
+            if case == 0 {
+                assert!(verified.is_empty());
+                continue;
+            }
+            assert_eq!(vec![0..case as u64], verified);
+            assert_eq!(input, output);
+
+            // Damage the first chunk, and everything after it is still recovered.
+            let first_chunk_offset =
+                HEADER_SIZE + PARENT_SIZE * encode::pre_order_parent_nodes(0, case as u64) as usize;
+            let mut bad_encoded = encoded.clone();
+            bad_encoded[first_chunk_offset] ^= 1;
+            let (verified, output) = run(&bad_encoded);
+            let first_end = cmp::min(case, CHUNK_SIZE);
+            if case > CHUNK_SIZE {
+                assert_eq!(vec![first_end as u64..case as u64], verified);
+            } else {
+                assert!(verified.is_empty());
+            }
+            assert!(output[..first_end].iter().all(|&b| b == 0xaa));
+            assert_eq!(&input[first_end..], &output[first_end..]);
+        }
+    }
+
+    #[test]
+    fn test_salvage_keyed_and_outboard() {
+        let key = [42; KEY_SIZE];
+        let input = make_test_input(5 * CHUNK_SIZE);
+        let mut bad_input = input.clone();
+        bad_input[CHUNK_SIZE] ^= 1;
+        let expected = vec![
+            0..CHUNK_SIZE as u64,
+            2 * CHUNK_SIZE as u64..input.len() as u64,
+        ];
+        let check = |verified: Vec<Range<u64>>, output: Vec<u8>| {
+            assert_eq!(expected, verified);
+            assert_eq!(&input[..CHUNK_SIZE], &output[..CHUNK_SIZE]);
+            assert_eq!(&input[2 * CHUNK_SIZE..], &output[2 * CHUNK_SIZE..]);
+        };
+
+        let (outboard, hash) = encode::outboard(&input);
+        let mut output = Cursor::new(vec![0; input.len()]);
+        let verified = salvage_outboard(
+            Cursor::new(&bad_input),
+            Cursor::new(&outboard),
+            &hash,
+            &mut output,
+        )
+        .unwrap();
+        check(verified, output.into_inner());
+
+        let (outboard, hash) = encode::outboard_keyed(&input, &key);
+        let mut output = Cursor::new(vec![0; input.len()]);
+        let verified = salvage_outboard_keyed(
+            Cursor::new(&bad_input),
+            Cursor::new(&outboard),
+            &hash,
+            &key,
+            &mut output,
+        )
+        .unwrap();
+        check(verified, output.into_inner());
+
+        let (mut encoded, hash) = encode::encode_keyed(&input, &key);
+        let (bad_encoded, _) = encode::encode(&bad_input);
+        let first_chunk_offset = HEADER_SIZE
+            + PARENT_SIZE * encode::pre_order_parent_nodes(0, input.len() as u64) as usize;
+        let second_chunk_offset = first_chunk_offset + CHUNK_SIZE;
+        encoded[second_chunk_offset] = bad_encoded[second_chunk_offset];
+        let mut output = Cursor::new(vec![0; input.len()]);
+        let verified = salvage_keyed(Cursor::new(&encoded), &hash, &key, &mut output).unwrap();
+        check(verified, output.into_inner());
+    }
+
+    // Build a combined encoding with a hostile length, where every node along the left edge of the
+    // tree is valid, so that decoding gets past the root and does real arithmetic on the length.
+    // Everything to the right of that edge is missing.
+    fn make_left_edge_encoding(content_len: u64) -> (Vec<u8>, Vec<u8>, Hash) {
+        let mut lens = vec![content_len];
+        while *lens.last().unwrap() > CHUNK_SIZE as u64 {
+            lens.push(encode::left_subtree_len(*lens.last().unwrap()));
+        }
+        let chunk = vec![0xab; *lens.last().unwrap() as usize];
+        let mut cv = crate::hash_chunk(0, &chunk, Finalization::NotRoot, None);
+        let mut parents = Vec::new();
+        for level in (0..lens.len() - 1).rev() {
+            let right_cv = Hash::from([level as u8; 32]);
+            let mut parent = cv.as_bytes().to_vec();
+            parent.extend_from_slice(right_cv.as_bytes());
+            let finalization = if level == 0 {
+                Finalization::Root
+            } else {
+                Finalization::NotRoot
+            };
+            cv = crate::parent_cv(&cv, &right_cv, finalization, None);
+            parents.push(parent);
+        }
+        let mut encoded = crate::encode_len(content_len).to_vec();
+        for parent in parents.iter().rev() {
+            encoded.extend_from_slice(parent);
+        }
+        encoded.extend_from_slice(&chunk);
+        (encoded, chunk, cv)
+    }
+
+    #[test]
+    fn test_hostile_lengths() {
+        let lens = [
+            CHUNK_SIZE as u64 + 1,
+            1 << 40,
+            1 << 63,
+            (1 << 63) + 1,
+            u64::MAX - CHUNK_SIZE as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ];
+        for &len in &lens {
+            println!("len {}", len);
+            let (encoded, chunk, hash) = make_left_edge_encoding(len);
+            let outboard = &encoded[..encoded.len() - chunk.len()];
+            for &outboard_mode in &[false, true] {
+                let make_decoder = || {
+                    if outboard_mode {
+                        Decoder::new_outboard(Cursor::new(&chunk[..]), Cursor::new(outboard), &hash)
+                    } else {
+                        Decoder::new(Cursor::new(&encoded[..]), &hash)
+                    }
+                };
+                // The first chunk is genuinely valid.
+                let mut decoder = make_decoder();
+                let mut buf = vec![0; chunk.len()];
+                decoder.read_exact(&mut buf).unwrap();
+                assert_eq!(chunk, buf);
+                // Everything else must fail cleanly.
+                assert!(decoder.read(&mut [0]).is_err());
+                let seeks = [
+                    SeekFrom::End(0),
+                    SeekFrom::End(-1),
+                    SeekFrom::Start(CHUNK_SIZE as u64),
+                    SeekFrom::Start(len / 2 + CHUNK_SIZE as u64),
+                    SeekFrom::Start(len - 1),
+                    SeekFrom::Start(u64::MAX),
+                ];
+                for &seek in &seeks {
+                    let mut decoder = make_decoder();
+                    let result = decoder.seek(seek).and_then(|_| decoder.read(&mut [0]));
+                    assert!(result.is_err(), "{:?}", seek);
+                }
+                let err = make_decoder().seek(SeekFrom::Current(-1)).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            }
+
+            // Slices of the valid first chunk work, and slices elsewhere fail cleanly.
+            let mut slice = Vec::new();
+            let mut extractor = encode::SliceExtractor::new(Cursor::new(&encoded), 0, 1);
+            extractor.read_to_end(&mut slice).unwrap();
+            let mut decoder = SliceDecoder::new(&*slice, &hash, 0, 1);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(&chunk[..1], &output[..]);
+            let mut extractor = encode::SliceExtractor::new(Cursor::new(&encoded), len - 1, 1);
+            assert!(extractor.read_to_end(&mut Vec::new()).is_err());
+
+            assert!(decode(&encoded, &hash).is_err());
+            assert!(root_hash(&*encoded).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+            let mut encoder = encode::Encoder::new_outboard_keyed(Cursor::new(Vec::new()), &key);
+            encoder.write_all(&input).unwrap();
+            let keyed_hash = encoder.finalize().unwrap();
+            let keyed_outboard = encoder.into_inner().into_inner();
+            let (expected, expected_keyed) = if case > CHUNK_SIZE {
+                (Some(hash), Some(keyed_hash))
+            } else {
+                (None, None)
+            };
+            assert_eq!(expected, root_hash(&*encoded).unwrap());
+            assert_eq!(expected, root_hash(&*outboard).unwrap());
+            assert_eq!(
+                expected_keyed,
+                root_hash_keyed(&*keyed_outboard, &key).unwrap()
+            );
+        }
+
+        // A truncated tree is an error.
+        let (outboard, _) = encode::outboard(make_test_input(2 * CHUNK_SIZE));
+        let err = root_hash(&outboard[..HEADER_SIZE + 1]).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
     #[test]
     fn test_decoders_corrupted() {
         for &case in crate::test::TEST_CASES {
@@ -846,6 +2270,121 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_next_chunk() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let mut decoder = Decoder::new(&*encoded, &hash);
+            let mut output = Vec::new();
+            while let Some((offset, chunk)) = decoder.next_chunk().unwrap() {
+                assert_eq!(output.len() as u64, offset);
+                assert!(chunk.len() <= CHUNK_SIZE);
+                output.extend_from_slice(chunk);
+            }
+            assert_eq!(input, output);
+
+            // Seeking partway into a chunk yields the rest of it.
+            let seek = case as u64 / 2;
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            decoder.seek(SeekFrom::Start(seek)).unwrap();
+            match decoder.next_chunk().unwrap() {
+                Some((offset, chunk)) => {
+                    assert_eq!(seek, offset);
+                    let end = cmp::min(case, (seek as usize / CHUNK_SIZE + 1) * CHUNK_SIZE);
+                    assert_eq!(&input[seek as usize..end], chunk);
+                }
+                None => assert_eq!(0, case),
+            }
+        }
+
+        // Corruption is reported, not yielded.
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (mut encoded, hash) = encode::encode(&input);
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        let mut verified = 0;
+        let err = loop {
+            match decoder.next_chunk() {
+                Ok(Some((_, chunk))) => verified += chunk.len(),
+                Ok(None) => panic!("corruption not detected"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(2 * CHUNK_SIZE, verified);
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let input: Vec<u8> = (0..1000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        let (encoded, hash) = encode::encode(&input);
+        let decoder = Decoder::new(&*encoded, &hash);
+        let lines: Vec<String> = decoder.lines().map(Result::unwrap).collect();
+        assert_eq!(1000, lines.len());
+        assert_eq!("line 999", lines[999]);
+
+        // Mix reads with fill_buf and consume.
+        let mut decoder = Decoder::new(&*encoded, &hash);
+        let mut output = vec![0; 10];
+        decoder.read_exact(&mut output).unwrap();
+        let buf = decoder.fill_buf().unwrap();
+        assert_eq!(&input[10..CHUNK_SIZE], buf);
+        decoder.consume(5);
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(&input[..10], &output[..10]);
+        assert_eq!(&input[15..], &output[10..]);
+        assert!(decoder.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_content_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, hash) = encode::encode(&input);
+            let (outboard, _) = encode::outboard(&input);
+
+            // Ask for the length partway through a read. The read should pick up where it was.
+            let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
+            let mut output = vec![0; case / 2];
+            decoder.read_exact(&mut output).unwrap();
+            assert_eq!(case as u64, decoder.content_len().unwrap());
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(input, output);
+
+            let mut decoder =
+                Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+            assert_eq!(case as u64, decoder.content_len().unwrap());
+
+            // Corrupting the final chunk makes the length untrustworthy.
+            if case > 0 {
+                let mut bad_encoded = encoded.clone();
+                let last = bad_encoded.len() - 1;
+                bad_encoded[last] ^= 1;
+                let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
+                let err = decoder.content_len().unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+                // After the error, a read picks up where it was, up to the corrupt chunk.
+                let last_chunk_start = (case - 1) / CHUNK_SIZE * CHUNK_SIZE;
+                if last_chunk_start > case / 2 {
+                    let mut decoder = Decoder::new(Cursor::new(&bad_encoded), &hash);
+                    let mut output = vec![0; case / 2];
+                    decoder.read_exact(&mut output).unwrap();
+                    decoder.content_len().unwrap_err();
+                    let mut rest = vec![0; last_chunk_start - case / 2];
+                    decoder.read_exact(&mut rest).unwrap();
+                    assert_eq!(&input[case / 2..last_chunk_start], &rest[..]);
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_invalid_zero_length() {
         // There are different ways of structuring a decoder, and many of them are vulnerable to a
@@ -905,7 +2444,7 @@ mod test {
             // Read all the bits up to that tweak. Because it's right after a chunk boundary, the
             // read should succeed.
             let mut decoder = Decoder::new(Cursor::new(&encoded), &hash);
-            let mut output = vec![0; tweak_position as usize];
+            let mut output = vec![0; tweak_position];
             decoder.read_exact(&mut output).unwrap();
             assert_eq!(&input[..tweak_position], &*output);
 
@@ -1079,6 +2618,168 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_verify_sample() {
+        let input_len = 0b100101 * CHUNK_SIZE;
+        let mut input = make_test_input(input_len);
+        let (outboard, hash) = encode::outboard(&input);
+        let mut prng = ChaChaRng::from_seed([0; 32]);
+        let report = verify_sample(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            100,
+            |total| prng.gen_range(0..total),
+        )
+        .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(input_len as u64, report.content_len);
+        assert_eq!(0b100101, report.total_chunks);
+        assert_eq!(100, report.chunks_sampled);
+
+        // Corrupt one chunk and sample every chunk in order.
+        input[5 * CHUNK_SIZE] ^= 1;
+        let mut next = 0;
+        let report = verify_sample(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            0b100101,
+            |_| {
+                next += 1;
+                next - 1
+            },
+        )
+        .unwrap();
+        assert_eq!(vec![5], report.corrupt_chunks);
+        assert!(!report.is_ok());
+        let p = report.detection_probability(0.1);
+        assert!(p > 0.97 && p < 0.99, "{}", p);
+
+        // Corrupt the last chunk instead. That's recorded, and sampling carries on.
+        input[5 * CHUNK_SIZE] ^= 1;
+        *input.last_mut().unwrap() ^= 1;
+        let mut next = 0;
+        let report = verify_sample(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            0b100101,
+            |_| {
+                next += 1;
+                next - 1
+            },
+        )
+        .unwrap();
+        assert_eq!(input_len as u64, report.content_len);
+        assert_eq!(0b100101 + 1, report.chunks_sampled);
+        assert_eq!(vec![0b100100, 0b100100], report.corrupt_chunks);
+    }
+
+    #[test]
+    fn test_verify_sample_keyed() {
+        let key = [42; KEY_SIZE];
+        let input = make_test_input(10 * CHUNK_SIZE);
+        let (outboard, hash) = encode::outboard_keyed(&input, &key);
+        let mut next = 0;
+        let report = verify_sample_keyed(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            &key,
+            10,
+            |_| {
+                next += 1;
+                next - 1
+            },
+        )
+        .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(10, report.chunks_sampled);
+
+        // Without the key, the final chunk and every sample fail.
+        let mut next = 0;
+        let report = verify_sample(
+            Cursor::new(&input),
+            Cursor::new(&outboard),
+            &hash,
+            10,
+            |_| {
+                next += 1;
+                next - 1
+            },
+        )
+        .unwrap();
+        assert_eq!(11, report.chunks_sampled);
+        assert_eq!(11, report.corrupt_chunks.len());
+    }
+
+    #[test]
+    fn test_scrubber() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (outboard, hash) = encode::outboard(&input);
+            let mut scrubber = Scrubber::new(Cursor::new(&input), Cursor::new(&outboard), &hash);
+            scrubber.run().unwrap();
+            assert_eq!(case as u64, scrubber.position());
+
+            // Corrupt the last byte. Everything before its chunk should verify.
+            if case > 0 {
+                let mut bad_input = input.clone();
+                *bad_input.last_mut().unwrap() ^= 1;
+                let mut scrubber =
+                    Scrubber::new(Cursor::new(&bad_input), Cursor::new(&outboard), &hash);
+                let err = scrubber.run().unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidData, err.kind());
+                let last_chunk_start = (case as u64 - 1) / CHUNK_SIZE as u64 * CHUNK_SIZE as u64;
+                assert_eq!(last_chunk_start, scrubber.position());
+            }
+        }
+    }
+
+    #[test]
+    fn test_scrubber_keyed() {
+        let key = [42; KEY_SIZE];
+        let input = make_test_input(10 * CHUNK_SIZE);
+        let (outboard, hash) = encode::outboard_keyed(&input, &key);
+        let mut scrubber =
+            Scrubber::new_keyed(Cursor::new(&input), Cursor::new(&outboard), &hash, &key);
+        scrubber.run().unwrap();
+        assert_eq!(input.len() as u64, scrubber.position());
+
+        // Without the key, nothing verifies.
+        let mut scrubber = Scrubber::new(Cursor::new(&input), Cursor::new(&outboard), &hash);
+        let err = scrubber.run().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(0, scrubber.position());
+    }
+
+    #[test]
+    fn test_scrubber_rate_limit_and_pause() {
+        let input = make_test_input(4 * SCRUB_BATCH_SIZE as usize);
+        let (outboard, hash) = encode::outboard(&input);
+        let mut scrubber = Scrubber::new(Cursor::new(input), Cursor::new(outboard), &hash);
+        // Four batches at eight batches per second should take at least half a second.
+        scrubber.set_rate_limit(Some(8 * SCRUB_BATCH_SIZE));
+        let start = Instant::now();
+        scrubber.run().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        let input = make_test_input(SCRUB_BATCH_SIZE as usize);
+        let (outboard, hash) = encode::outboard(&input);
+        let mut scrubber = Scrubber::new(Cursor::new(input), Cursor::new(outboard), &hash);
+        let pause_handle = scrubber.pause_handle();
+        pause_handle.pause();
+        assert!(pause_handle.is_paused());
+        let start = Instant::now();
+        let thread = thread::spawn(move || scrubber.run());
+        thread::sleep(Duration::from_millis(100));
+        pause_handle.resume();
+        thread.join().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
     #[test]
     fn test_into_inner() {
         let v = vec![1u8, 2, 3];
@@ -25,47 +25,134 @@
 //! let (encoded_at_once, hash) = bao::encode::encode(b"some input");
 //! assert_eq!(expected_hash, hash);
 //!
-//! let mut encoded_incrementally = Vec::new();
-//! let mut encoder = bao::encode::Encoder::new(Cursor::new(&mut encoded_incrementally));
+//! let mut encoder = bao::encode::Encoder::new(Cursor::new(Vec::new()));
 //! encoder.write_all(b"some input")?;
 //! let hash = encoder.finalize()?;
 //! assert_eq!(expected_hash, hash);
 //!
+//! let encoded_incrementally = encoder.into_inner().into_inner();
 //! assert_eq!(encoded_at_once, encoded_incrementally);
 //! # Ok(())
 //! # }
 //! ```
 
 use crate::Finalization::{self, NotRoot, Root};
-use crate::{Hash, ParentNode, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, MAX_DEPTH, PARENT_SIZE};
-use arrayref::array_mut_ref;
+use crate::{
+    Hash, ParentNode, CHUNK_SIZE, HASH_SIZE, HEADER_SIZE, KEY_SIZE, MAX_DEPTH, PARENT_SIZE,
+};
+use arrayref::{array_mut_ref, array_ref};
 use arrayvec::ArrayVec;
 use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::mem;
+use std::ops::Range;
 
 /// Encode an entire slice into a bytes vector in the default combined mode.
 /// This is a convenience wrapper around `Encoder::write_all`.
 pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
-    let bytes = input.as_ref();
-    let mut vec = Vec::with_capacity(encoded_size(bytes.len() as u64) as usize);
-    let mut encoder = Encoder::new(io::Cursor::new(&mut vec));
+    encode_inner(input.as_ref(), None)
+}
+
+/// Like `encode`, but using the keyed mode. The root hash will be the same as
+/// `blake3::keyed_hash` of the input. This is a convenience wrapper around
+/// `Encoder::new_keyed`.
+pub fn encode_keyed(input: impl AsRef<[u8]>, key: &[u8; KEY_SIZE]) -> (Vec<u8>, Hash) {
+    encode_inner(input.as_ref(), Some(key))
+}
+
+fn encode_inner(bytes: &[u8], key: Option<&[u8; KEY_SIZE]>) -> (Vec<u8>, Hash) {
+    // Input of one chunk or less has no parent nodes, so the encoding is just the header and the
+    // input. Skip the Encoder and its flip pass, which dominate the cost of tiny inputs.
+    if bytes.len() <= CHUNK_SIZE {
+        let mut vec = Vec::with_capacity(HEADER_SIZE + bytes.len());
+        vec.extend_from_slice(&crate::encode_len(bytes.len() as u64));
+        vec.extend_from_slice(bytes);
+        return (vec, crate::hash_chunk(0, bytes, Root, key));
+    }
+    let vec = Vec::with_capacity(encoded_size(bytes.len() as u64) as usize);
+    let mut encoder = match key {
+        Some(key) => Encoder::new_keyed(io::Cursor::new(vec), key),
+        None => Encoder::new(io::Cursor::new(vec)),
+    };
     encoder.write_all(bytes).unwrap();
     let hash = encoder.finalize().unwrap();
-    (vec, hash)
+    (encoder.into_inner().into_inner(), hash)
 }
 
 /// Encode an entire slice into a bytes vector in the outboard mode. This is a
 /// convenience wrapper around `Encoder::new_outboard` and `Encoder::write_all`.
 pub fn outboard(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
-    let bytes = input.as_ref();
-    let mut vec = Vec::with_capacity(outboard_size(bytes.len() as u64) as usize);
-    let mut encoder = Encoder::new_outboard(io::Cursor::new(&mut vec));
+    outboard_inner(input.as_ref(), None)
+}
+
+/// Like `outboard`, but using the keyed mode. This is a convenience wrapper
+/// around `Encoder::new_outboard_keyed`.
+pub fn outboard_keyed(input: impl AsRef<[u8]>, key: &[u8; KEY_SIZE]) -> (Vec<u8>, Hash) {
+    outboard_inner(input.as_ref(), Some(key))
+}
+
+fn outboard_inner(bytes: &[u8], key: Option<&[u8; KEY_SIZE]>) -> (Vec<u8>, Hash) {
+    // As in encode, skip the Encoder for input of one chunk or less.
+    if bytes.len() <= CHUNK_SIZE {
+        let header = crate::encode_len(bytes.len() as u64);
+        return (header.to_vec(), crate::hash_chunk(0, bytes, Root, key));
+    }
+    let vec = Vec::with_capacity(outboard_size(bytes.len() as u64) as usize);
+    let mut encoder = match key {
+        Some(key) => Encoder::new_outboard_keyed(io::Cursor::new(vec), key),
+        None => Encoder::new_outboard(io::Cursor::new(vec)),
+    };
     encoder.write_all(bytes).unwrap();
     let hash = encoder.finalize().unwrap();
-    (vec, hash)
+    (encoder.into_inner().into_inner(), hash)
+}
+
+/// Encode an entire slice into a caller-provided buffer in the default combined mode, without
+/// allocating. The encoding goes in the first `encoded_size(input.len())` bytes of `output`, and
+/// any bytes after that are left alone. If `output` is too short, this returns an `InvalidInput`
+/// error without writing anything.
+pub fn encode_to_slice(input: impl AsRef<[u8]>, output: &mut [u8]) -> io::Result<Hash> {
+    let input = input.as_ref();
+    let encoded_len = encoded_size(input.len() as u64);
+    if (output.len() as u128) < encoded_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "output buffer too small",
+        ));
+    }
+    // The flip in finalize seeks from the end, so the cursor has to cover exactly the encoding.
+    let mut encoder = Encoder::new(io::Cursor::new(&mut output[..encoded_len as usize]));
+    encoder.write_all(input)?;
+    encoder.finalize()
+}
+
+/// Encode the contents of a vector in place, in the default combined mode. Like `encode`, but
+/// rather than allocating a second buffer, this grows `buf` to `encoded_size` and rearranges it.
+pub fn encode_in_place(buf: &mut Vec<u8>) -> Hash {
+    let content_len = buf.len();
+    let encoded_len = encoded_size(content_len as u64) as usize;
+    // Move the content to the end, and then feed it to an Encoder that writes from the front. The
+    // Encoder's post-order output never catches up with the input it hasn't read yet, because
+    // that's always preceded by room for every parent node and the header. The post-order
+    // output also ends up exactly the size of the buffer, so finalize can flip it in place.
+    let content_start = encoded_len - content_len;
+    buf.resize(encoded_len, 0);
+    buf.copy_within(..content_len, content_start);
+    let mut encoder = Encoder::new(io::Cursor::new(mem::take(buf)));
+    let mut chunk = [0; CHUNK_SIZE];
+    for start in (content_start..encoded_len).step_by(CHUNK_SIZE) {
+        let len = cmp::min(CHUNK_SIZE, encoded_len - start);
+        chunk[..len].copy_from_slice(&encoder.inner_mut().get_ref()[start..][..len]);
+        encoder.write_all(&chunk[..len]).unwrap();
+    }
+    let hash = encoder.finalize().unwrap();
+    *buf = encoder.into_inner().into_inner();
+    debug_assert_eq!(encoded_len, buf.len());
+    hash
 }
 
 /// Compute the size of a combined encoding, given the size of the input. Note that for input sizes
@@ -74,6 +161,37 @@ pub fn encoded_size(content_len: u64) -> u128 {
     content_len as u128 + outboard_size(content_len)
 }
 
+/// Compute the size of the slice that `SliceExtractor` produces for the given content length,
+/// `slice_start`, and `slice_len`, without reading anything.
+///
+/// This follows the slice rules in the spec: a `slice_len` of zero is treated as one, a
+/// `slice_start` at or past the end of the content selects the final chunk, and the slice is
+/// capped at the end of the content.
+pub fn slice_size(content_len: u64, slice_start: u64, slice_len: u64) -> u128 {
+    if content_len == 0 {
+        return HEADER_SIZE as u128;
+    }
+    let start = cmp::min(slice_start, content_len - 1);
+    let end = cmp::min(start.saturating_add(cmp::max(slice_len, 1)), content_len);
+    HEADER_SIZE as u128 + slice_subtree_size(0, content_len, start, end)
+}
+
+// Subtrees that lie entirely within the slice are included whole, and subtrees that don't overlap
+// it are skipped, so this only recurses along the two edges of the slice.
+fn slice_subtree_size(subtree_start: u64, subtree_len: u64, start: u64, end: u64) -> u128 {
+    let subtree_end = subtree_start + subtree_len;
+    if subtree_end <= start || end <= subtree_start {
+        0
+    } else if (start <= subtree_start && subtree_end <= end) || subtree_len <= CHUNK_SIZE as u64 {
+        encoded_subtree_size(subtree_len)
+    } else {
+        let left_len = left_subtree_len(subtree_len);
+        PARENT_SIZE as u128
+            + slice_subtree_size(subtree_start, left_len, start, end)
+            + slice_subtree_size(subtree_start + left_len, subtree_len - left_len, start, end)
+    }
+}
+
 /// Compute the size of an outboard encoding, given the size of the input.
 pub fn outboard_size(content_len: u64) -> u128 {
     // Should the return type here really by u128? Two reasons: 1) It's convenient to use the same
@@ -98,7 +216,7 @@ pub(crate) fn count_chunks(content_len: u64) -> u64 {
     // Two things to watch out for here: the 0-length input still counts as 1 chunk, and we don't
     // want to overflow when content_len is u64::MAX_VALUE.
     let full_chunks: u64 = content_len / CHUNK_SIZE as u64;
-    let has_partial_chunk: bool = (content_len % CHUNK_SIZE as u64) != 0;
+    let has_partial_chunk: bool = content_len % CHUNK_SIZE as u64 != 0;
     cmp::max(1, full_chunks + has_partial_chunk as u64)
 }
 
@@ -107,6 +225,15 @@ pub(crate) fn chunk_size(chunk_index: u64, content_len: u64) -> usize {
     cmp::min(CHUNK_SIZE, (content_len - chunk_start) as usize)
 }
 
+// The left subtree of a parent node contains the largest power of two number of chunks that
+// leaves at least one byte for the right subtree.
+pub(crate) fn left_subtree_len(content_len: u64) -> u64 {
+    debug_assert!(content_len > CHUNK_SIZE as u64);
+    let full_chunks = (content_len - 1) / CHUNK_SIZE as u64;
+    let left_chunks = 1 << (63 - full_chunks.leading_zeros());
+    left_chunks * CHUNK_SIZE as u64
+}
+
 // ----------------------------------------------------------------------------
 // When flipping the post-order tree to pre-order during encoding, and when
 // traversing the pre-order tree during decoding, we need to know how many
@@ -279,6 +406,7 @@ pub(crate) enum StateFinish {
 pub(crate) struct State {
     subtrees: ArrayVec<Hash, MAX_DEPTH>,
     total_len: u64,
+    key: Option<[u8; KEY_SIZE]>,
 }
 
 impl State {
@@ -286,17 +414,29 @@ impl State {
         Self {
             subtrees: ArrayVec::new(),
             total_len: 0,
+            key: None,
         }
     }
 
+    /// Create a `State` for the keyed mode. Callers must hash their chunks with the same key.
+    pub fn new_keyed(key: &[u8; KEY_SIZE]) -> Self {
+        let mut state = Self::new();
+        state.key = Some(*key);
+        state
+    }
+
     pub fn count(&self) -> u64 {
         self.total_len
     }
 
+    pub fn key(&self) -> Option<&[u8; KEY_SIZE]> {
+        self.key.as_ref()
+    }
+
     fn merge_inner(&mut self, finalization: Finalization) -> ParentNode {
         let right_child = self.subtrees.pop().unwrap();
         let left_child = self.subtrees.pop().unwrap();
-        let parent_cv = blake3::guts::parent_cv(&left_child, &right_child, finalization.is_root());
+        let parent_cv = crate::parent_cv(&left_child, &right_child, finalization, self.key());
         self.subtrees.push(parent_cv);
         let mut parent_node = [0; PARENT_SIZE];
         parent_node[..HASH_SIZE].copy_from_slice(left_child.as_bytes());
@@ -380,7 +520,7 @@ impl State {
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Avoid printing hashes, they might be secret.
+        // Avoid printing hashes or the key, they might be secret.
         write!(f, "State {{ ... }}")
     }
 }
@@ -389,7 +529,14 @@ impl fmt::Debug for State {
 /// done writing.
 ///
 /// `Encoder` supports both combined and outboard encoding, depending on which
-/// constructor you use.
+/// constructor you use, or both at once with `new_tee`. Each of those also has
+/// a keyed variant, which uses the BLAKE3 keyed hash for every node in the
+/// tree. A keyed encoding can only be decoded by a `Decoder` with the same key.
+///
+/// Input doesn't need to be contiguous or aligned to chunk boundaries. Each
+/// call to `write` can supply any number of bytes, so the pieces of a rope or
+/// a piece table can be written one after another without concatenating them
+/// first, and the result is the same as writing the whole input at once.
 ///
 /// # Example
 ///
@@ -405,27 +552,39 @@ impl fmt::Debug for State {
 /// # Ok(())
 /// # }
 /// ```
-#[derive(Clone, Debug)]
+///
+/// Until `finalize` succeeds, the output is an intermediate post-order layout that isn't a valid
+/// encoding. If you might stop early, because of an error or a crash in another part of your
+/// program, use `set_abort_hook` to clean up the partial output. The hook runs when you call
+/// `abort`, or when the `Encoder` is dropped without being finalized.
+///
+/// Because `Encoder` implements `Drop`, a writer that borrows something, like
+/// `Cursor::new(&mut vec)`, keeps it borrowed until the `Encoder` is dropped or `into_inner` is
+/// called, even after `finalize`.
 pub struct Encoder<T: Read + Write + Seek> {
-    inner: T,
-    chunk_state: blake3::guts::ChunkState,
+    // This is only None after into_inner, which needs to move the writer out past our Drop impl.
+    inner: Option<T>,
+    // The second output of a tee encoder. It gets the same parent nodes and header as inner, but
+    // no chunks, so that it ends up as an outboard encoding.
+    tee: Option<T>,
+    chunk_state: blake3::Hasher,
     tree_state: State,
     outboard: bool,
     finalized: bool,
+    // Set once finalize has succeeded or abort has run. Until then, Drop runs the abort hook.
+    done: bool,
+    abort_hook: Option<AbortHook<T>>,
 }
 
+// Boxed so that the hook can capture state, like the path of the output file.
+type AbortHook<T> = Box<dyn FnMut(&mut T) -> io::Result<()> + Send + Sync>;
+
 impl<T: Read + Write + Seek> Encoder<T> {
     /// Create a new `Encoder` that will produce a combined encoding.The encoding will contain all
     /// the input bytes, so that it can be decoded without the original input file. This is what
     /// you get from `bao encode`.
     pub fn new(inner: T) -> Self {
-        Self {
-            inner,
-            chunk_state: blake3::guts::ChunkState::new(0),
-            tree_state: State::new(),
-            outboard: false,
-            finalized: false,
-        }
+        Self::new_inner(inner, None, State::new(), false)
     }
 
     /// Create a new `Encoder` for making an outboard encoding. That means that the encoding won't
@@ -433,9 +592,54 @@ impl<T: Read + Write + Seek> Encoder<T> {
     /// when the outboard encoding is later decoded. This is what you get from `bao encode
     /// --outboard`.
     pub fn new_outboard(inner: T) -> Self {
-        let mut encoder = Self::new(inner);
-        encoder.outboard = true;
-        encoder
+        Self::new_inner(inner, None, State::new(), true)
+    }
+
+    /// Like `new`, but using the keyed mode. The root hash will be the same as
+    /// `blake3::keyed_hash` of the input.
+    pub fn new_keyed(inner: T, key: &[u8; KEY_SIZE]) -> Self {
+        Self::new_inner(inner, None, State::new_keyed(key), false)
+    }
+
+    /// Like `new_outboard`, but using the keyed mode. The root hash will be the same as
+    /// `blake3::keyed_hash` of the input.
+    pub fn new_outboard_keyed(inner: T, key: &[u8; KEY_SIZE]) -> Self {
+        Self::new_inner(inner, None, State::new_keyed(key), true)
+    }
+
+    /// Create a new `Encoder` that writes a combined encoding to `combined` and an outboard
+    /// encoding of the same input to `outboard`, in a single pass. Use `into_inner_and_outboard`
+    /// to get both writers back. An abort hook runs on both of them.
+    pub fn new_tee(combined: T, outboard: T) -> Self {
+        Self::new_inner(combined, Some(outboard), State::new(), false)
+    }
+
+    /// Like `new_tee`, but using the keyed mode.
+    pub fn new_tee_keyed(combined: T, outboard: T, key: &[u8; KEY_SIZE]) -> Self {
+        Self::new_inner(combined, Some(outboard), State::new_keyed(key), false)
+    }
+
+    fn new_inner(inner: T, tee: Option<T>, tree_state: State, outboard: bool) -> Self {
+        Self {
+            inner: Some(inner),
+            tee,
+            chunk_state: crate::chunk_hasher(0, tree_state.key()),
+            tree_state,
+            outboard,
+            finalized: false,
+            done: false,
+            abort_hook: None,
+        }
+    }
+
+    /// The number of input bytes written so far. After `finalize`, this is the content length
+    /// recorded in the header, and `encoded_size` or `outboard_size` of it gives the size of the
+    /// output.
+    pub fn content_len(&self) -> u64 {
+        self.tree_state
+            .count()
+            .checked_add(self.chunk_state.count())
+            .expect("addition overflowed")
     }
 
     /// Finalize the encoding, after all the input has been written. You can't keep using this
@@ -451,25 +655,27 @@ impl<T: Read + Write + Seek> Encoder<T> {
 
         // Compute the total len before we merge the final chunk into the
         // tree_state.
-        let total_len = self
-            .tree_state
-            .count()
-            .checked_add(self.chunk_state.len() as u64)
-            .expect("addition overflowed");
+        let total_len = self.content_len();
 
         // Finalize the last chunk. Note that any partial chunk bytes retained in the chunk_state
         // have already been written to the underlying writer by .write().
-        debug_assert!(self.chunk_state.len() > 0 || self.tree_state.count() == 0);
-        let last_chunk_is_root = self.tree_state.count() == 0;
-        let last_chunk_hash = self.chunk_state.finalize(last_chunk_is_root);
+        debug_assert!(self.chunk_state.count() > 0 || self.tree_state.count() == 0);
+        let last_chunk_finalization = if self.tree_state.count() == 0 {
+            Root
+        } else {
+            NotRoot
+        };
+        let last_chunk_hash = crate::finalize_chunk(&self.chunk_state, last_chunk_finalization);
         self.tree_state
-            .push_subtree(&last_chunk_hash, self.chunk_state.len());
+            .push_subtree(&last_chunk_hash, self.chunk_state.count() as usize);
+        // The last chunk now counts towards tree_state, so clear it here to keep content_len right.
+        self.chunk_state = crate::chunk_hasher(0, self.tree_state.key());
 
         // Merge and write all the parents along the right edge.
         let root_hash;
         loop {
             match self.tree_state.merge_finalize() {
-                StateFinish::Parent(parent) => self.inner.write_all(&parent)?,
+                StateFinish::Parent(parent) => self.write_tree_bytes(&parent)?,
                 StateFinish::Root(root) => {
                     root_hash = root;
                     break;
@@ -478,66 +684,206 @@ impl<T: Read + Write + Seek> Encoder<T> {
         }
 
         // Write the length header, at the end.
-        self.inner.write_all(&crate::encode_len(total_len))?;
+        self.write_tree_bytes(&crate::encode_len(total_len))?;
 
         // Finally, flip the tree to be pre-order. This means rewriting the
         // entire output, so it's expensive.
-        self.flip_post_order_stream()?;
+        let outboard = self.outboard;
+        flip_post_order_stream(self.inner_mut(), outboard)?;
+        if let Some(tee) = &mut self.tee {
+            flip_post_order_stream(tee, true)?;
+        }
 
+        self.done = true;
         Ok(root_hash)
     }
 
-    /// Return the underlying writer.
+    /// Set a function to clean up the partial output, if this `Encoder` is aborted or dropped
+    /// before `finalize` succeeds. For example, a file-backed encoder could truncate the file with
+    /// `|file: &mut File| file.set_len(0)`, or delete it with
+    /// `move |_: &mut File| std::fs::remove_file(&path)`.
+    ///
+    /// The hook runs at most once per writer. A tee encoder calls it for the combined writer and
+    /// then for the outboard writer.
+    pub fn set_abort_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut T) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.abort_hook = Some(Box::new(hook));
+    }
+
+    /// Abandon the encoding without finalizing it, and run the abort hook if one was set. You
+    /// can't keep using this `Encoder` after calling `abort`, and writing or finalizing will
+    /// panic. It's also an error to call `abort` after `finalize` has succeeded, and that will
+    /// panic too.
+    ///
+    /// Dropping an unfinalized `Encoder` has the same effect, except that any error returned by
+    /// the hook is ignored.
+    pub fn abort(&mut self) -> io::Result<()> {
+        assert!(!self.done, "already finished");
+        self.finalized = true;
+        self.done = true;
+        let mut hook = match self.abort_hook.take() {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        let inner_result = self.inner.as_mut().map_or(Ok(()), &mut hook);
+        let tee_result = self.tee.as_mut().map_or(Ok(()), hook);
+        inner_result.and(tee_result)
+    }
+
+    fn inner_mut(&mut self) -> &mut T {
+        self.inner.as_mut().expect("writer taken")
+    }
+
+    // Parent nodes and the header go to both outputs of a tee encoder.
+    fn write_tree_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner_mut().write_all(bytes)?;
+        if let Some(tee) = &mut self.tee {
+            tee.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Return the underlying writer. This doesn't run the abort hook, even if the encoding was
+    /// never finalized.
     pub fn into_inner(self) -> T {
-        self.inner
+        self.into_inner_and_outboard().0
     }
 
-    fn flip_post_order_stream(&mut self) -> io::Result<()> {
-        let mut write_cursor = self.inner.seek(SeekFrom::End(0))?;
-        let mut read_cursor = write_cursor - HEADER_SIZE as u64;
-        let mut header = [0; HEADER_SIZE];
-        self.inner.seek(SeekFrom::Start(read_cursor))?;
-        self.inner.read_exact(&mut header)?;
-        let content_len = crate::decode_len(&header);
-        let mut flipper = FlipperState::new(content_len);
-        loop {
-            match flipper.next() {
-                FlipperNext::FeedParent => {
-                    let mut parent = [0; PARENT_SIZE];
-                    self.inner
-                        .seek(SeekFrom::Start(read_cursor - PARENT_SIZE as u64))?;
-                    self.inner.read_exact(&mut parent)?;
-                    read_cursor -= PARENT_SIZE as u64;
-                    flipper.feed_parent(parent);
-                }
-                FlipperNext::TakeParent => {
-                    let parent = flipper.take_parent();
-                    self.inner
-                        .seek(SeekFrom::Start(write_cursor - PARENT_SIZE as u64))?;
-                    self.inner.write_all(&parent)?;
-                    write_cursor -= PARENT_SIZE as u64;
-                }
-                FlipperNext::Chunk(size) => {
-                    // In outboard moded, we skip over chunks.
-                    if !self.outboard {
-                        let mut chunk = [0; CHUNK_SIZE];
-                        self.inner
-                            .seek(SeekFrom::Start(read_cursor - size as u64))?;
-                        self.inner.read_exact(&mut chunk[..size])?;
-                        read_cursor -= size as u64;
-                        self.inner
-                            .seek(SeekFrom::Start(write_cursor - size as u64))?;
-                        self.inner.write_all(&chunk[..size])?;
-                        write_cursor -= size as u64;
-                    }
-                    flipper.chunk_moved();
-                }
-                FlipperNext::Done => {
-                    debug_assert_eq!(HEADER_SIZE as u64, write_cursor);
-                    self.inner.seek(SeekFrom::Start(0))?;
-                    self.inner.write_all(&header)?;
-                    return Ok(());
+    /// Return the underlying writer, along with the outboard writer if this `Encoder` was created
+    /// with `new_tee`. Like `into_inner`, this doesn't run the abort hook.
+    pub fn into_inner_and_outboard(mut self) -> (T, Option<T>) {
+        // Skip the abort hook in Drop.
+        self.done = true;
+        (self.inner.take().expect("writer taken"), self.tee.take())
+    }
+}
+
+impl<T: Read + Write + Seek + fmt::Debug> fmt::Debug for Encoder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Encoder")
+            .field("inner", &self.inner)
+            .field("tee", &self.tee)
+            .field("chunk_state", &self.chunk_state)
+            .field("tree_state", &self.tree_state)
+            .field("outboard", &self.outboard)
+            .field("finalized", &self.finalized)
+            .field("done", &self.done)
+            .field("abort_hook", &self.abort_hook.is_some())
+            .finish()
+    }
+}
+
+/// Equivalent to `Encoder::new(T::default())`, for example to encode into a fresh
+/// `Cursor<Vec<u8>>`.
+impl<T: Read + Write + Seek + Default> Default for Encoder<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Read + Write + Seek> Drop for Encoder<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            // There's no way to report an error from drop.
+            let _ = self.abort();
+        }
+    }
+}
+
+/// Flip a combined encoding from post-order into the standard pre-order layout, in place.
+///
+/// In the post-order layout, each chunk is followed by the parent nodes that it completes, and the
+/// 8-byte length header comes last. That's what an encoder can write without knowing the length of
+/// its input in advance, and `Encoder` writes this layout internally before flipping it in
+/// `finalize`. Tools that build post-order trees themselves, for example by appending to a file,
+/// can use this function to turn them into standard encodings.
+///
+/// The stream must contain exactly one post-order encoding, starting at offset 0. This returns
+/// `InvalidData` if its size doesn't match the length in the header. It doesn't verify any hashes,
+/// and garbage in will produce garbage out.
+pub fn flip_post_order(mut encoding: impl Read + Write + Seek) -> io::Result<()> {
+    check_post_order_size(&mut encoding, false)?;
+    flip_post_order_stream(&mut encoding, false)
+}
+
+/// Like `flip_post_order`, but for an outboard tree, which has the same layout without the chunks.
+pub fn flip_post_order_outboard(mut outboard: impl Read + Write + Seek) -> io::Result<()> {
+    check_post_order_size(&mut outboard, true)?;
+    flip_post_order_stream(&mut outboard, true)
+}
+
+fn check_post_order_size(inner: &mut (impl Read + Seek), outboard: bool) -> io::Result<()> {
+    let stream_len = inner.seek(SeekFrom::End(0))?;
+    let bad_size = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "wrong size for the length header",
+        )
+    };
+    if stream_len < HEADER_SIZE as u64 {
+        return Err(bad_size());
+    }
+    let mut header = [0; HEADER_SIZE];
+    inner.seek(SeekFrom::Start(stream_len - HEADER_SIZE as u64))?;
+    inner.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    let expected_len = if outboard {
+        outboard_size(content_len)
+    } else {
+        encoded_size(content_len)
+    };
+    if stream_len as u128 != expected_len {
+        return Err(bad_size());
+    }
+    Ok(())
+}
+
+fn flip_post_order_stream(
+    inner: &mut (impl Read + Write + Seek),
+    outboard: bool,
+) -> io::Result<()> {
+    let mut write_cursor = inner.seek(SeekFrom::End(0))?;
+    let mut read_cursor = write_cursor - HEADER_SIZE as u64;
+    let mut header = [0; HEADER_SIZE];
+    inner.seek(SeekFrom::Start(read_cursor))?;
+    inner.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    let mut flipper = FlipperState::new(content_len);
+    loop {
+        match flipper.next() {
+            FlipperNext::FeedParent => {
+                let mut parent = [0; PARENT_SIZE];
+                inner.seek(SeekFrom::Start(read_cursor - PARENT_SIZE as u64))?;
+                inner.read_exact(&mut parent)?;
+                read_cursor -= PARENT_SIZE as u64;
+                flipper.feed_parent(parent);
+            }
+            FlipperNext::TakeParent => {
+                let parent = flipper.take_parent();
+                inner.seek(SeekFrom::Start(write_cursor - PARENT_SIZE as u64))?;
+                inner.write_all(&parent)?;
+                write_cursor -= PARENT_SIZE as u64;
+            }
+            FlipperNext::Chunk(size) => {
+                // In outboard moded, we skip over chunks.
+                if !outboard {
+                    let mut chunk = [0; CHUNK_SIZE];
+                    inner.seek(SeekFrom::Start(read_cursor - size as u64))?;
+                    inner.read_exact(&mut chunk[..size])?;
+                    read_cursor -= size as u64;
+                    inner.seek(SeekFrom::Start(write_cursor - size as u64))?;
+                    inner.write_all(&chunk[..size])?;
+                    write_cursor -= size as u64;
                 }
+                flipper.chunk_moved();
+            }
+            FlipperNext::Done => {
+                debug_assert_eq!(HEADER_SIZE as u64, write_cursor);
+                inner.seek(SeekFrom::Start(0))?;
+                inner.write_all(&header)?;
+                return Ok(());
             }
         }
     }
@@ -554,29 +900,649 @@ impl<T: Read + Write + Seek> Write for Encoder<T> {
 
         // If the current chunk is full, we need to finalize it, add it to
         // the tree state, and write out any completed parent nodes.
-        if self.chunk_state.len() == CHUNK_SIZE {
+        if self.chunk_state.count() == CHUNK_SIZE as u64 {
             // This can't be the root, because we know more input is coming.
-            let chunk_hash = self.chunk_state.finalize(false);
+            let chunk_hash = crate::finalize_chunk(&self.chunk_state, NotRoot);
             self.tree_state.push_subtree(&chunk_hash, CHUNK_SIZE);
             let chunk_counter = self.tree_state.count() / CHUNK_SIZE as u64;
-            self.chunk_state = blake3::guts::ChunkState::new(chunk_counter);
+            self.chunk_state = crate::chunk_hasher(chunk_counter, self.tree_state.key());
             while let Some(parent) = self.tree_state.merge_parent() {
-                self.inner.write_all(&parent)?;
+                self.write_tree_bytes(&parent)?;
             }
         }
 
         // Add as many bytes as possible to the current chunk.
-        let want = CHUNK_SIZE - self.chunk_state.len();
+        let want = CHUNK_SIZE - self.chunk_state.count() as usize;
         let take = cmp::min(want, input.len());
         if !self.outboard {
-            self.inner.write_all(&input[..take])?;
+            self.inner_mut().write_all(&input[..take])?;
         }
         self.chunk_state.update(&input[..take]);
         Ok(take)
     }
 
+    // The default write_vectored only writes the first non-empty buffer. Consume all of them, so
+    // that callers with scattered buffers don't need to flatten them first.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            let mut buf = &buf[..];
+            while !buf.is_empty() {
+                match self.write(buf) {
+                    Ok(n) => {
+                        total += n;
+                        buf = &buf[n..];
+                    }
+                    Err(e) if total == 0 => return Err(e),
+                    Err(_) => return Ok(total),
+                }
+            }
+        }
+        Ok(total)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        self.inner_mut().flush()?;
+        if let Some(tee) = &mut self.tee {
+            tee.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Update an outboard encoding in place after some of its content has changed, and return the new
+/// root hash.
+///
+/// `changed` lists the byte ranges of `content` that have been modified since `outboard` was
+/// written. Only the chunks that overlap those ranges get re-hashed, along with the parent nodes
+/// above them, so the cost is proportional to the size of the edits times the depth of the tree,
+/// rather than to the size of the whole content. The content length must not have changed. After
+/// an insertion, a deletion, or an append, re-encode the content from scratch.
+///
+/// Parent nodes that aren't above a changed range are trusted as-is. If the outboard didn't match
+/// the content before the edit, the returned hash won't match it either.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let mut content = vec![0; 100_000];
+/// let (outboard, _) = bao::encode::outboard(&content);
+///
+/// content[50_000..50_010].copy_from_slice(b"some edits");
+/// let mut outboard_cursor = Cursor::new(outboard);
+/// let hash = bao::encode::update_outboard(
+///     Cursor::new(&content),
+///     &mut outboard_cursor,
+///     &[50_000..50_010],
+/// )?;
+///
+/// assert_eq!(bao::encode::outboard(&content), (outboard_cursor.into_inner(), hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn update_outboard(
+    content: impl Read + Seek,
+    outboard: impl Read + Write + Seek,
+    changed: &[Range<u64>],
+) -> io::Result<Hash> {
+    update_outboard_inner(content, outboard, changed, None)
+}
+
+/// Like `update_outboard`, but for an outboard encoding made with `Encoder::new_outboard_keyed`
+/// or `outboard_keyed`. Using the wrong key, or none, corrupts the outboard.
+pub fn update_outboard_keyed(
+    content: impl Read + Seek,
+    outboard: impl Read + Write + Seek,
+    changed: &[Range<u64>],
+    key: &[u8; KEY_SIZE],
+) -> io::Result<Hash> {
+    update_outboard_inner(content, outboard, changed, Some(key))
+}
+
+fn update_outboard_inner(
+    mut content: impl Read + Seek,
+    mut outboard: impl Read + Write + Seek,
+    changed: &[Range<u64>],
+    key: Option<&[u8; KEY_SIZE]>,
+) -> io::Result<Hash> {
+    let mut header = [0; HEADER_SIZE];
+    outboard.seek(SeekFrom::Start(0))?;
+    outboard.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    if content.seek(SeekFrom::End(0))? != content_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "content length doesn't match the outboard",
+        ));
+    }
+    let mut rehasher = Rehasher {
+        content: Some(content),
+        encoding: outboard,
+        changed,
+        patch: None,
+        key: key.copied(),
+    };
+    rehasher.check_ranges(content_len)?;
+    rehasher.subtree(0, content_len, HEADER_SIZE as u128, Root)
+}
+
+/// Overwrite part of the content of a combined encoding, and return the new root hash.
+///
+/// `new_content` replaces the content bytes starting at `offset`. Only the chunks it overlaps are
+/// rewritten, along with the parent nodes above them, as in `update_outboard`. The content length
+/// can't change, so `offset + new_content.len()` must be within the current length, or this
+/// returns an `InvalidInput` error.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let mut content = vec![0; 100_000];
+/// let (encoded, _) = bao::encode::encode(&content);
+///
+/// let mut encoded_cursor = Cursor::new(encoded);
+/// let hash = bao::encode::update_encoded(&mut encoded_cursor, 50_000, b"some edits")?;
+///
+/// content[50_000..50_010].copy_from_slice(b"some edits");
+/// assert_eq!(bao::encode::encode(&content), (encoded_cursor.into_inner(), hash));
+/// # Ok(())
+/// # }
+/// ```
+pub fn update_encoded(
+    encoding: impl Read + Write + Seek,
+    offset: u64,
+    new_content: &[u8],
+) -> io::Result<Hash> {
+    update_encoded_inner(encoding, offset, new_content, None)
+}
+
+/// Like `update_encoded`, but for an encoding made with `Encoder::new_keyed` or `encode_keyed`.
+/// Using the wrong key, or none, corrupts the encoding.
+pub fn update_encoded_keyed(
+    encoding: impl Read + Write + Seek,
+    offset: u64,
+    new_content: &[u8],
+    key: &[u8; KEY_SIZE],
+) -> io::Result<Hash> {
+    update_encoded_inner(encoding, offset, new_content, Some(key))
+}
+
+fn update_encoded_inner(
+    mut encoding: impl Read + Write + Seek,
+    offset: u64,
+    new_content: &[u8],
+    key: Option<&[u8; KEY_SIZE]>,
+) -> io::Result<Hash> {
+    let mut header = [0; HEADER_SIZE];
+    encoding.seek(SeekFrom::Start(0))?;
+    encoding.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    let end = offset
+        .checked_add(new_content.len() as u64)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "changed range out of bounds")
+        })?;
+    let changed = offset..end;
+    let mut rehasher = Rehasher::<io::Empty, _> {
+        content: None,
+        encoding,
+        changed: std::slice::from_ref(&changed),
+        patch: Some((offset, new_content)),
+        key: key.copied(),
+    };
+    rehasher.check_ranges(content_len)?;
+    rehasher.subtree(0, content_len, HEADER_SIZE as u128, Root)
+}
+
+/// Split a combined encoding into its content and an outboard encoding, without hashing anything.
+///
+/// This reads the encoding once from start to finish, writing chunks to `content` and the header
+/// and parent nodes to `outboard`. Nothing is verified, so a damaged encoding produces damaged
+/// output. Decode the result, or the original encoding, if it isn't already trusted. A truncated
+/// encoding returns an `UnexpectedEof` error.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let input = vec![0; 100_000];
+/// let (encoded, _) = bao::encode::encode(&input);
+/// let mut content = Vec::new();
+/// let mut outboard = Vec::new();
+/// bao::encode::split_encoded(&*encoded, &mut content, &mut outboard)?;
+/// assert_eq!(input, content);
+/// assert_eq!(bao::encode::outboard(&input).0, outboard);
+/// # Ok(())
+/// # }
+/// ```
+pub fn split_encoded(
+    mut encoded: impl Read,
+    mut content: impl Write,
+    mut outboard: impl Write,
+) -> io::Result<()> {
+    let mut header = [0; HEADER_SIZE];
+    encoded.read_exact(&mut header)?;
+    outboard.write_all(&header)?;
+    split_subtree(
+        &mut encoded,
+        &mut content,
+        &mut outboard,
+        crate::decode_len(&header),
+    )
+}
+
+fn split_subtree(
+    encoded: &mut impl Read,
+    content: &mut impl Write,
+    outboard: &mut impl Write,
+    len: u64,
+) -> io::Result<()> {
+    if len <= CHUNK_SIZE as u64 {
+        let mut chunk = [0; CHUNK_SIZE];
+        encoded.read_exact(&mut chunk[..len as usize])?;
+        return content.write_all(&chunk[..len as usize]);
+    }
+    let mut parent = [0; PARENT_SIZE];
+    encoded.read_exact(&mut parent)?;
+    outboard.write_all(&parent)?;
+    let left_len = left_subtree_len(len);
+    split_subtree(encoded, content, outboard, left_len)?;
+    split_subtree(encoded, content, outboard, len - left_len)
+}
+
+/// Merge content and its outboard encoding into a combined encoding, without hashing anything.
+///
+/// This is the inverse of `split_encoded`. It's a convenience wrapper around a `SliceExtractor`
+/// covering the entire content, and like that type it doesn't verify anything.
+pub fn combine_outboard(
+    content: impl Read + Seek,
+    mut outboard: impl Read + Seek,
+    mut encoded: impl Write,
+) -> io::Result<()> {
+    let mut header = [0; HEADER_SIZE];
+    outboard.seek(SeekFrom::Start(0))?;
+    outboard.read_exact(&mut header)?;
+    let content_len = crate::decode_len(&header);
+    outboard.seek(SeekFrom::Start(0))?;
+    let mut extractor = SliceExtractor::new_outboard(content, outboard, 0, content_len);
+    io::copy(&mut extractor, &mut encoded)?;
+    Ok(())
+}
+
+/// Compare two outboard encodings and return the ranges of content that differ between them.
+///
+/// This only reads parent nodes. Wherever two subtrees have the same hash, the whole subtree is
+/// skipped, so comparing two large files with a few small differences only reads a few paths
+/// through each tree. Adjacent differing chunks are merged into a single range, and every range
+/// is aligned to chunk boundaries.
+///
+/// Both encodings must describe content of the same length, or this returns an
+/// `InvalidInput` error. Content of one chunk or less has no parent nodes, so there's nothing to
+/// compare. In that case the whole content is returned as a single range, and the caller should
+/// compare the content directly. Neither tree is verified against a root hash.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let a = vec![0; 100_000];
+/// let mut b = a.clone();
+/// b[50_000] = 1;
+/// let (a_outboard, _) = bao::encode::outboard(&a);
+/// let (b_outboard, _) = bao::encode::outboard(&b);
+/// let ranges = bao::encode::diff_outboards(
+///     std::io::Cursor::new(a_outboard),
+///     std::io::Cursor::new(b_outboard),
+/// )?;
+/// assert_eq!(vec![49_152..50_176], ranges);
+/// # Ok(())
+/// # }
+/// ```
+pub fn diff_outboards(a: impl Read + Seek, b: impl Read + Seek) -> io::Result<Vec<Range<u64>>> {
+    diff_trees(a, b, true)
+}
+
+/// Compare two combined encodings and return the ranges of content that differ between them.
+/// This works like `diff_outboards`, except that content of one chunk or less is compared
+/// directly, since a combined encoding includes it.
+pub fn diff_encoded(a: impl Read + Seek, b: impl Read + Seek) -> io::Result<Vec<Range<u64>>> {
+    diff_trees(a, b, false)
+}
+
+fn diff_trees(
+    mut a: impl Read + Seek,
+    mut b: impl Read + Seek,
+    outboard: bool,
+) -> io::Result<Vec<Range<u64>>> {
+    let mut headers = [[0; HEADER_SIZE]; 2];
+    a.seek(SeekFrom::Start(0))?;
+    a.read_exact(&mut headers[0])?;
+    b.seek(SeekFrom::Start(0))?;
+    b.read_exact(&mut headers[1])?;
+    if headers[0] != headers[1] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "content lengths differ",
+        ));
+    }
+    let content_len = crate::decode_len(&headers[0]);
+    let mut ranges = Vec::new();
+    if content_len > CHUNK_SIZE as u64 {
+        let mut differ = TreeDiffer {
+            a,
+            b,
+            outboard,
+            ranges: &mut ranges,
+        };
+        differ.subtree(0, content_len, HEADER_SIZE as u128)?;
+    } else if content_len > 0 {
+        let mut chunks = [[0; CHUNK_SIZE]; 2];
+        let size = content_len as usize;
+        if !outboard {
+            a.read_exact(&mut chunks[0][..size])?;
+            b.read_exact(&mut chunks[1][..size])?;
+        }
+        if outboard || chunks[0][..size] != chunks[1][..size] {
+            ranges.push(0..content_len);
+        }
+    }
+    Ok(ranges)
+}
+
+struct TreeDiffer<'a, A, B> {
+    a: A,
+    b: B,
+    outboard: bool,
+    ranges: &'a mut Vec<Range<u64>>,
+}
+
+impl<A: Read + Seek, B: Read + Seek> TreeDiffer<'_, A, B> {
+    fn push_range(&mut self, start: u64, len: u64) {
+        if let Some(last) = self.ranges.last_mut() {
+            if last.end == start {
+                last.end += len;
+                return;
+            }
+        }
+        self.ranges.push(start..start + len);
+    }
+
+    // The caller has already checked that this subtree is larger than a chunk.
+    fn subtree(&mut self, start: u64, len: u64, encoding_position: u128) -> io::Result<()> {
+        let position = cast_offset(encoding_position)?;
+        let mut parents = [[0; PARENT_SIZE]; 2];
+        self.a.seek(SeekFrom::Start(position))?;
+        self.a.read_exact(&mut parents[0])?;
+        self.b.seek(SeekFrom::Start(position))?;
+        self.b.read_exact(&mut parents[1])?;
+        let left_len = left_subtree_len(len);
+        let left_position = encoding_position + PARENT_SIZE as u128;
+        let right_position = left_position
+            + if self.outboard {
+                outboard_subtree_size(left_len)
+            } else {
+                encoded_subtree_size(left_len)
+            };
+        let children = [
+            (start, left_len, left_position),
+            (start + left_len, len - left_len, right_position),
+        ];
+        for (i, &(child_start, child_len, child_position)) in children.iter().enumerate() {
+            let cv_range = i * HASH_SIZE..(i + 1) * HASH_SIZE;
+            if parents[0][cv_range.clone()] == parents[1][cv_range] {
+                continue;
+            }
+            if child_len <= CHUNK_SIZE as u64 {
+                self.push_range(child_start, child_len);
+            } else {
+                self.subtree(child_start, child_len, child_position)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Statistics from `dedup_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// The total content length of all the outboards scanned.
+    pub total_bytes: u64,
+    /// The number of content bytes that remain if every repeated subtree is stored only once.
+    pub unique_bytes: u64,
+    /// The number of subtrees compared.
+    pub total_subtrees: u64,
+    /// The number of distinct subtrees among them.
+    pub unique_subtrees: u64,
+}
+
+impl DedupStats {
+    /// The number of bytes that deduplication would save, `total_bytes - unique_bytes`.
+    pub fn savings(&self) -> u64 {
+        self.total_bytes - self.unique_bytes
+    }
+}
+
+/// Scan a set of outboard encodings and estimate how much space deduplicating their subtrees
+/// would save.
+///
+/// Each tree is cut into the subtrees `depth` levels below its root, or into smaller subtrees
+/// where a branch ends in a chunk before that depth. Subtrees with the same hash are counted once.
+/// Only parent nodes are read. A `depth` of 1 compares the two halves of each root, and each
+/// additional level doubles the number of subtrees and halves their size.
+///
+/// Note that BLAKE3 mixes each chunk's position into its hash, so only identical content at the
+/// same offset shows up as shared. This is the case for successive versions of a disk image, for
+/// example, but not for data that has shifted. Content of one chunk or less has no parent nodes,
+/// and it's always counted as unique. The outboards aren't verified.
+///
+/// # Panics
+///
+/// Panics if `depth` is zero.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::io::Cursor;
+///
+/// let old_image = vec![0; 1 << 20];
+/// let mut new_image = old_image.clone();
+/// new_image[1000] = 1;
+/// let old_outboard = Cursor::new(bao::encode::outboard(&old_image).0);
+/// let new_outboard = Cursor::new(bao::encode::outboard(&new_image).0);
+/// let stats = bao::encode::dedup_stats(vec![old_outboard, new_outboard], 4)?;
+/// // Only the first sixteenth of the new image differs.
+/// assert_eq!(15 * (1 << 16), stats.savings());
+/// # Ok(())
+/// # }
+/// ```
+pub fn dedup_stats<R: Read + Seek>(
+    outboards: impl IntoIterator<Item = R>,
+    depth: u32,
+) -> io::Result<DedupStats> {
+    assert!(depth > 0, "depth must be at least 1");
+    let mut stats = DedupStats::default();
+    let mut seen = HashMap::new();
+    let mut subtrees = Vec::new();
+    for mut outboard in outboards {
+        let mut header = [0; HEADER_SIZE];
+        outboard.seek(SeekFrom::Start(0))?;
+        outboard.read_exact(&mut header)?;
+        let content_len = crate::decode_len(&header);
+        stats.total_bytes += content_len;
+        if content_len <= CHUNK_SIZE as u64 {
+            stats.unique_bytes += content_len;
+            continue;
+        }
+        subtrees.clear();
+        collect_subtrees(
+            &mut outboard,
+            content_len,
+            HEADER_SIZE as u64,
+            depth,
+            &mut subtrees,
+        )?;
+        for &(hash, len) in &subtrees {
+            stats.total_subtrees += 1;
+            if seen.insert(hash, len).is_none() {
+                stats.unique_subtrees += 1;
+                stats.unique_bytes += len;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+// Collect the hash and length of each subtree `depth` levels below this one. The caller has
+// already checked that this subtree is larger than a chunk. Outboard positions always fit in a
+// u64.
+fn collect_subtrees(
+    outboard: &mut (impl Read + Seek),
+    len: u64,
+    outboard_position: u64,
+    depth: u32,
+    subtrees: &mut Vec<(Hash, u64)>,
+) -> io::Result<()> {
+    let mut parent = [0; PARENT_SIZE];
+    outboard.seek(SeekFrom::Start(outboard_position))?;
+    outboard.read_exact(&mut parent)?;
+    let left_len = left_subtree_len(len);
+    let left_position = outboard_position + PARENT_SIZE as u64;
+    let right_position = left_position + outboard_subtree_size(left_len) as u64;
+    let children = [
+        (left_len, left_position, array_ref!(parent, 0, HASH_SIZE)),
+        (
+            len - left_len,
+            right_position,
+            array_ref!(parent, HASH_SIZE, HASH_SIZE),
+        ),
+    ];
+    for &(child_len, child_position, child_cv) in &children {
+        if depth == 1 || child_len <= CHUNK_SIZE as u64 {
+            subtrees.push((Hash::from(*child_cv), child_len));
+        } else {
+            collect_subtrees(outboard, child_len, child_position, depth - 1, subtrees)?;
+        }
+    }
+    Ok(())
+}
+
+// Walks the pre-order tree from the root, descending only into subtrees that overlap a changed
+// range, and rewrites the parent nodes along the way. In outboard mode, chunks are read from the
+// separate content reader. Otherwise they're read from the encoding itself, and any overlapping
+// bytes from the patch are written into them first.
+struct Rehasher<'a, C, E> {
+    content: Option<C>,
+    encoding: E,
+    changed: &'a [Range<u64>],
+    patch: Option<(u64, &'a [u8])>,
+    key: Option<[u8; KEY_SIZE]>,
+}
+
+impl<C: Read + Seek, E: Read + Write + Seek> Rehasher<'_, C, E> {
+    fn check_ranges(&self, content_len: u64) -> io::Result<()> {
+        for range in self.changed {
+            if range.start > range.end || range.end > content_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "changed range out of bounds",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_changed(&self, start: u64, len: u64) -> bool {
+        let end = start + len;
+        self.changed
+            .iter()
+            .any(|range| range.start < end && start < range.end && !range.is_empty())
+    }
+
+    fn subtree_size(&self, len: u64) -> u128 {
+        if self.content.is_some() {
+            outboard_subtree_size(len)
+        } else {
+            encoded_subtree_size(len)
+        }
+    }
+
+    fn subtree(
+        &mut self,
+        start: u64,
+        len: u64,
+        encoding_position: u128,
+        finalization: Finalization,
+    ) -> io::Result<Hash> {
+        let key = self.key;
+        if len <= CHUNK_SIZE as u64 {
+            let mut buf = [0; CHUNK_SIZE];
+            let chunk = &mut buf[..len as usize];
+            match &mut self.content {
+                Some(content) => {
+                    content.seek(SeekFrom::Start(start))?;
+                    content.read_exact(chunk)?;
+                }
+                None => {
+                    let position = cast_offset(encoding_position)?;
+                    self.encoding.seek(SeekFrom::Start(position))?;
+                    self.encoding.read_exact(chunk)?;
+                    if let Some((patch_start, patch)) = self.patch {
+                        let from = cmp::max(start, patch_start);
+                        let to = cmp::min(start + len, patch_start + patch.len() as u64);
+                        if from < to {
+                            chunk[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                                &patch[(from - patch_start) as usize..(to - patch_start) as usize],
+                            );
+                            self.encoding.seek(SeekFrom::Start(position))?;
+                            self.encoding.write_all(chunk)?;
+                        }
+                    }
+                }
+            }
+            let chunk_index = start / CHUNK_SIZE as u64;
+            return Ok(crate::hash_chunk(
+                chunk_index,
+                chunk,
+                finalization,
+                key.as_ref(),
+            ));
+        }
+        let mut parent = [0; PARENT_SIZE];
+        let parent_position = cast_offset(encoding_position)?;
+        self.encoding.seek(SeekFrom::Start(parent_position))?;
+        self.encoding.read_exact(&mut parent)?;
+        let mut left_cv = Hash::from(*array_ref!(parent, 0, HASH_SIZE));
+        let mut right_cv = Hash::from(*array_ref!(parent, HASH_SIZE, HASH_SIZE));
+        let left_len = left_subtree_len(len);
+        let left_position = encoding_position + PARENT_SIZE as u128;
+        let right_position = left_position + self.subtree_size(left_len);
+        let mut parent_changed = false;
+        if self.is_changed(start, left_len) {
+            left_cv = self.subtree(start, left_len, left_position, NotRoot)?;
+            parent_changed = true;
+        }
+        if self.is_changed(start + left_len, len - left_len) {
+            right_cv = self.subtree(start + left_len, len - left_len, right_position, NotRoot)?;
+            parent_changed = true;
+        }
+        if parent_changed {
+            parent[..HASH_SIZE].copy_from_slice(left_cv.as_bytes());
+            parent[HASH_SIZE..].copy_from_slice(right_cv.as_bytes());
+            self.encoding.seek(SeekFrom::Start(parent_position))?;
+            self.encoding.write_all(&parent)?;
+        }
+        Ok(crate::parent_cv(
+            &left_cv,
+            &right_cv,
+            finalization,
+            key.as_ref(),
+        ))
     }
 }
 
@@ -984,7 +1950,8 @@ pub(crate) enum LenNext {
 ///
 /// Extracting a slice doesn't re-hash any of the bytes. As a result, it's fast compared to
 /// decoding. You can quickly convert an outboard encoding to a combined encoding by "extracting" a
-/// slice with a `slice_start` of zero and a `slice_len` equal to the original input length.
+/// slice with a `slice_start` of zero and a `slice_len` equal to the original input length, which
+/// is what `combine_outboard` does.
 ///
 /// See the `decode` module for decoding slices.
 ///
@@ -1183,11 +2150,8 @@ impl<T: Read + Seek, O: Read + Seek> Read for SliceExtractor<T, O> {
 }
 
 pub(crate) fn cast_offset(offset: u128) -> io::Result<u64> {
-    if offset > u64::max_value() as u128 {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "seek offset overflowed u64",
-        ))
+    if offset > u64::MAX as u128 {
+        Err(io::Error::other("seek offset overflowed u64"))
     } else {
         Ok(offset as u64)
     }
@@ -1228,6 +2192,482 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_encode_to_slice() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode(&input);
+            let mut output = vec![0xff; expected_encoded.len() + 1];
+            let hash = encode_to_slice(&input, &mut output).unwrap();
+            assert_eq!(expected_hash, hash);
+            assert_eq!(expected_encoded[..], output[..expected_encoded.len()]);
+            assert_eq!(0xff, *output.last().unwrap());
+
+            let short_len = expected_encoded.len() - 1;
+            let err = encode_to_slice(&input, &mut output[..short_len]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_slice_size() {
+        for &case in crate::test::TEST_CASES {
+            let input = make_test_input(case);
+            let (outboard, _) = outboard(&input);
+            for &slice_start in crate::test::TEST_CASES {
+                let slice_lens = [0, 1, CHUNK_SIZE as u64, 3 * CHUNK_SIZE as u64 + 1, u64::MAX];
+                for &slice_len in slice_lens.iter() {
+                    println!("case {} start {} len {}", case, slice_start, slice_len);
+                    let mut slice = Vec::new();
+                    SliceExtractor::new_outboard(
+                        io::Cursor::new(&input),
+                        io::Cursor::new(&outboard),
+                        slice_start as u64,
+                        slice_len,
+                    )
+                    .read_to_end(&mut slice)
+                    .unwrap();
+                    let size = slice_size(case as u64, slice_start as u64, slice_len);
+                    assert_eq!(slice.len() as u128, size);
+                }
+            }
+            // A slice of everything is the combined encoding.
+            assert_eq!(
+                encoded_size(case as u64),
+                slice_size(case as u64, 0, case as u64)
+            );
+        }
+        assert_eq!(encoded_size(u64::MAX), slice_size(u64::MAX, 0, u64::MAX));
+    }
+
+    #[test]
+    fn test_encode_in_place() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut buf = input.clone();
+            let hash = encode_in_place(&mut buf);
+            assert_eq!(encode(&input), (buf, hash));
+        }
+    }
+
+    // A writer that refuses reads, so that Encoder::finalize fails as soon as it starts flipping,
+    // leaving the complete post-order layout behind.
+    struct NoReads(io::Cursor<Vec<u8>>);
+
+    impl Read for NoReads {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("no reads"))
+        }
+    }
+
+    impl Write for NoReads {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for NoReads {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_flip_post_order() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            for &outboard in &[false, true] {
+                let inner = NoReads(io::Cursor::new(Vec::new()));
+                let mut encoder = if outboard {
+                    Encoder::new_outboard(inner)
+                } else {
+                    Encoder::new(inner)
+                };
+                encoder.write_all(&input).unwrap();
+                encoder.finalize().unwrap_err();
+                let mut post_order = encoder.into_inner().0;
+                if outboard {
+                    flip_post_order_outboard(&mut post_order).unwrap();
+                    assert_eq!(self::outboard(&input).0, post_order.into_inner());
+                } else {
+                    flip_post_order(&mut post_order).unwrap();
+                    assert_eq!(encode(&input).0, post_order.into_inner());
+                }
+            }
+        }
+
+        // The size has to match the header.
+        let mut post_order = encode(make_test_input(CHUNK_SIZE)).0;
+        post_order.push(0);
+        let err = flip_post_order(io::Cursor::new(&mut post_order)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = flip_post_order(io::Cursor::new(vec![0; HEADER_SIZE - 1])).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_small_input_fast_path() {
+        for &case in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1] {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            assert_eq!((encoder.into_inner().into_inner(), hash), encode(&input));
+            let mut encoder = Encoder::new_outboard(io::Cursor::new(Vec::new()));
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            assert_eq!((encoder.into_inner().into_inner(), hash), outboard(&input));
+        }
+    }
+
+    #[test]
+    fn test_keyed_encode() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let expected_hash = blake3::keyed_hash(&key, &input);
+            let mut encoder = Encoder::new_keyed(io::Cursor::new(Vec::new()), &key);
+            encoder.write_all(&input).unwrap();
+            assert_eq!(expected_hash, encoder.finalize().unwrap());
+            let mut encoder = Encoder::new_outboard_keyed(io::Cursor::new(Vec::new()), &key);
+            encoder.write_all(&input).unwrap();
+            assert_eq!(expected_hash, encoder.finalize().unwrap());
+            // The tree layout doesn't depend on the key.
+            let outboard = encoder.into_inner().into_inner();
+            assert_eq!(outboard.len() as u128, outboard_size(case as u64));
+
+            // The all-at-once wrappers, including their small input fast path, should agree.
+            assert_eq!((outboard, expected_hash), outboard_keyed(&input, &key));
+            let (encoded, hash) = encode_keyed(&input, &key);
+            assert_eq!(expected_hash, hash);
+            assert_eq!(encoded.len() as u128, encoded_size(case as u64));
+        }
+    }
+
+    #[test]
+    fn test_content_len() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut encoder = Encoder::new_outboard(io::Cursor::new(Vec::new()));
+            assert_eq!(0, encoder.content_len());
+            // Check the count on both sides of each chunk boundary.
+            for piece in input.chunks(CHUNK_SIZE / 2 + 1) {
+                let before = encoder.content_len();
+                encoder.write_all(piece).unwrap();
+                assert_eq!(before + piece.len() as u64, encoder.content_len());
+            }
+            encoder.finalize().unwrap();
+            assert_eq!(case as u64, encoder.content_len());
+            let outboard = encoder.into_inner().into_inner();
+            assert_eq!(outboard_size(case as u64), outboard.len() as u128);
+        }
+    }
+
+    #[test]
+    fn test_default_encoder() {
+        let input = make_test_input(3 * CHUNK_SIZE + 1);
+        let mut encoder = Encoder::<io::Cursor<Vec<u8>>>::default();
+        encoder.write_all(&input).unwrap();
+        let hash = encoder.finalize().unwrap();
+        assert_eq!(encode(&input), (encoder.into_inner().into_inner(), hash));
+    }
+
+    #[test]
+    fn test_segmented_writes() {
+        // Segment sizes that straddle chunk boundaries in different ways.
+        let segment_sizes = [1, CHUNK_SIZE - 1, 2, CHUNK_SIZE, 3 * CHUNK_SIZE + 7, 0, 5];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_encoded, expected_hash) = encode(&input);
+            let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+            let mut remaining = &input[..];
+            for &size in segment_sizes.iter().cycle() {
+                if remaining.is_empty() {
+                    break;
+                }
+                let take = cmp::min(size, remaining.len());
+                encoder.write_all(&remaining[..take]).unwrap();
+                remaining = &remaining[take..];
+            }
+            assert_eq!(expected_hash, encoder.finalize().unwrap());
+            assert_eq!(expected_encoded, encoder.into_inner().into_inner());
+        }
+    }
+
+    #[test]
+    fn test_write_vectored() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (expected_outboard, expected_hash) = outboard(&input);
+            let thirds = [case / 3, 2 * case / 3];
+            let slices = [
+                io::IoSlice::new(&input[..thirds[0]]),
+                io::IoSlice::new(&[]),
+                io::IoSlice::new(&input[thirds[0]..thirds[1]]),
+                io::IoSlice::new(&input[thirds[1]..]),
+            ];
+            let mut encoder = Encoder::new_outboard(io::Cursor::new(Vec::new()));
+            assert_eq!(case, encoder.write_vectored(&slices).unwrap());
+            assert_eq!(expected_hash, encoder.finalize().unwrap());
+            assert_eq!(expected_outboard, encoder.into_inner().into_inner());
+        }
+    }
+
+    #[test]
+    fn test_update_outboard() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let mut input = make_test_input(case);
+            let (outboard_vec, hash) = outboard(&input);
+
+            // With no changes, the outboard stays the same.
+            let mut outboard_cursor = io::Cursor::new(outboard_vec.clone());
+            let new_hash =
+                update_outboard(io::Cursor::new(&input), &mut outboard_cursor, &[]).unwrap();
+            assert_eq!(hash, new_hash);
+            assert_eq!(outboard_vec, outboard_cursor.into_inner());
+
+            // Edit the first byte, the last byte, and a byte in the middle.
+            let mut changed = Vec::new();
+            for &position in &[0, case / 2, case.saturating_sub(1)] {
+                if position < case {
+                    input[position] ^= 1;
+                    changed.push(position as u64..position as u64 + 1);
+                }
+            }
+            let mut outboard_cursor = io::Cursor::new(outboard_vec);
+            let new_hash =
+                update_outboard(io::Cursor::new(&input), &mut outboard_cursor, &changed).unwrap();
+            let (expected_outboard, expected_hash) = outboard(&input);
+            assert_eq!(expected_hash, new_hash);
+            assert_eq!(expected_outboard, outboard_cursor.into_inner());
+        }
+    }
+
+    #[test]
+    fn test_update_outboard_keyed() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let mut input = make_test_input(case);
+            let (outboard_vec, _) = outboard_keyed(&input, &key);
+            let mut changed = Vec::new();
+            if case > 0 {
+                input[case / 2] ^= 1;
+                changed.push(case as u64 / 2..case as u64 / 2 + 1);
+            }
+            let mut outboard_cursor = io::Cursor::new(outboard_vec);
+            let hash = update_outboard_keyed(
+                io::Cursor::new(&input),
+                &mut outboard_cursor,
+                &changed,
+                &key,
+            )
+            .unwrap();
+            let outboard_vec = outboard_cursor.into_inner();
+            assert_eq!(outboard_keyed(&input, &key), (outboard_vec.clone(), hash));
+            let mut output = Vec::new();
+            crate::decode::Decoder::new_outboard_keyed(&*input, &*outboard_vec, &hash, &key)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(input, output);
+        }
+    }
+
+    #[test]
+    fn test_update_outboard_bad_input() {
+        let input = make_test_input(3 * CHUNK_SIZE);
+        let (outboard_vec, _) = outboard(&input);
+        let mut outboard_cursor = io::Cursor::new(outboard_vec);
+        let err =
+            update_outboard(io::Cursor::new(&input[1..]), &mut outboard_cursor, &[]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let out_of_bounds = 3 * CHUNK_SIZE as u64..3 * CHUNK_SIZE as u64 + 1;
+        let err = update_outboard(
+            io::Cursor::new(&input),
+            &mut outboard_cursor,
+            &[out_of_bounds],
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_update_encoded() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, _) = encode(&input);
+            // Edits at the start, in the middle straddling chunk boundaries, and at the end.
+            let edit_len = cmp::min(case, CHUNK_SIZE + 2);
+            for &offset in &[0, cmp::min(case / 3, case - edit_len), case - edit_len] {
+                let patch = vec![0xff; edit_len];
+                let mut encoded_cursor = io::Cursor::new(encoded.clone());
+                let hash = update_encoded(&mut encoded_cursor, offset as u64, &patch).unwrap();
+                let mut expected_input = input.clone();
+                expected_input[offset..][..edit_len].copy_from_slice(&patch);
+                assert_eq!(encode(&expected_input), (encoded_cursor.into_inner(), hash));
+            }
+        }
+
+        let (encoded, _) = encode(make_test_input(3 * CHUNK_SIZE));
+        let mut encoded_cursor = io::Cursor::new(encoded);
+        let err = update_encoded(&mut encoded_cursor, 3 * CHUNK_SIZE as u64, &[0]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = update_encoded(&mut encoded_cursor, u64::MAX, &[0]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_update_encoded_keyed() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let mut input = make_test_input(case);
+            let (encoded, _) = encode_keyed(&input, &key);
+            let edit_len = cmp::min(case, 10);
+            let offset = (case - edit_len) / 2;
+            let patch = vec![0xff; edit_len];
+            let mut encoded_cursor = io::Cursor::new(encoded);
+            let hash =
+                update_encoded_keyed(&mut encoded_cursor, offset as u64, &patch, &key).unwrap();
+            input[offset..][..edit_len].copy_from_slice(&patch);
+            let encoded = encoded_cursor.into_inner();
+            assert_eq!(encode_keyed(&input, &key), (encoded.clone(), hash));
+            assert_eq!(
+                input,
+                crate::decode::decode_keyed(&encoded, &hash, &key).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_and_combine() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let (encoded, _) = encode(&input);
+            let (expected_outboard, _) = outboard(&input);
+
+            let mut content = Vec::new();
+            let mut outboard = Vec::new();
+            split_encoded(&*encoded, &mut content, &mut outboard).unwrap();
+            assert_eq!(input, content);
+            assert_eq!(expected_outboard, outboard);
+
+            let mut combined = Vec::new();
+            combine_outboard(
+                io::Cursor::new(&content),
+                io::Cursor::new(&outboard),
+                &mut combined,
+            )
+            .unwrap();
+            assert_eq!(encoded, combined);
+
+            let truncated = &encoded[..encoded.len() - 1];
+            let err = split_encoded(truncated, io::sink(), io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let a = make_test_input(case);
+            let (a_encoded, _) = encode(&a);
+            let (a_outboard, _) = outboard(&a);
+            let diff = |b: &[u8]| {
+                let (b_encoded, _) = encode(b);
+                let (b_outboard, _) = outboard(b);
+                let encoded_ranges =
+                    diff_encoded(io::Cursor::new(&a_encoded), io::Cursor::new(&b_encoded)).unwrap();
+                let outboard_ranges =
+                    diff_outboards(io::Cursor::new(&a_outboard), io::Cursor::new(&b_outboard))
+                        .unwrap();
+                if case > CHUNK_SIZE {
+                    assert_eq!(encoded_ranges, outboard_ranges);
+                }
+                encoded_ranges
+            };
+
+            assert!(diff(&a).is_empty());
+            if case == 0 {
+                continue;
+            }
+            // Change the first and last bytes.
+            let mut b = a.clone();
+            b[0] ^= 1;
+            if case > 1 {
+                b[case - 1] ^= 1;
+            }
+            let last_chunk_start = (case - 1) / CHUNK_SIZE * CHUNK_SIZE;
+            let mut expected = Vec::new();
+            if last_chunk_start <= CHUNK_SIZE {
+                expected.push(0..case as u64);
+            } else {
+                expected.push(0..CHUNK_SIZE as u64);
+                expected.push(last_chunk_start as u64..case as u64);
+            }
+            assert_eq!(expected, diff(&b));
+        }
+
+        let a = make_test_input(10 * CHUNK_SIZE);
+        let b = make_test_input(11 * CHUNK_SIZE);
+        let err =
+            diff_encoded(io::Cursor::new(encode(&a).0), io::Cursor::new(encode(&b).0)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_dedup_stats() {
+        let a = make_test_input(8 * CHUNK_SIZE + 1);
+        let mut b = a.clone();
+        b[0] ^= 1;
+        let outboards = [
+            outboard(&a).0,
+            outboard(&b).0,
+            outboard(&a).0,
+            outboard(b"x").0,
+        ];
+        let cursors = || outboards.iter().map(io::Cursor::new);
+        let total_bytes = 3 * a.len() as u64 + 1;
+
+        // At depth 1, the left halves (8 chunks) differ between a and b, and the right halves (1
+        // byte) are the same. The second copy of a is entirely redundant.
+        let stats = dedup_stats(cursors(), 1).unwrap();
+        let expected = DedupStats {
+            total_bytes,
+            unique_bytes: 2 * 8 * CHUNK_SIZE as u64 + 1 + 1,
+            total_subtrees: 6,
+            unique_subtrees: 3,
+        };
+        assert_eq!(expected, stats);
+        assert_eq!(8 * CHUNK_SIZE as u64 + 2, stats.savings());
+
+        // At depth 4, only the first chunk of b is different.
+        let stats = dedup_stats(cursors(), 4).unwrap();
+        assert_eq!(total_bytes, stats.total_bytes);
+        assert_eq!(9 * CHUNK_SIZE as u64 + 2, stats.unique_bytes);
+        assert_eq!(3 * 9, stats.total_subtrees);
+        assert_eq!(10, stats.unique_subtrees);
+
+        // Depths below the bottom of the tree stop at the chunks.
+        assert_eq!(stats, dedup_stats(cursors(), 40).unwrap());
+    }
+
     fn largest_power_of_two_leq(n: u64) -> u64 {
         ((n / 2) + 1).next_power_of_two()
     }
@@ -1292,13 +2732,15 @@ mod test {
     }
 
     fn drive_state(mut input: &[u8]) -> Hash {
-        let last_chunk_is_root = input.len() <= CHUNK_SIZE;
+        let last_chunk_finalization = if input.len() <= CHUNK_SIZE {
+            Root
+        } else {
+            NotRoot
+        };
         let mut state = State::new();
         let mut chunk_index = 0;
         while input.len() > CHUNK_SIZE {
-            let hash = blake3::guts::ChunkState::new(chunk_index)
-                .update(&input[..CHUNK_SIZE])
-                .finalize(false);
+            let hash = crate::hash_chunk(chunk_index, &input[..CHUNK_SIZE], NotRoot, None);
             chunk_index += 1;
             state.push_subtree(&hash, CHUNK_SIZE);
             input = &input[CHUNK_SIZE..];
@@ -1306,9 +2748,7 @@ mod test {
             // them, but we need to avoid tripping an assert.
             while state.merge_parent().is_some() {}
         }
-        let hash = blake3::guts::ChunkState::new(chunk_index)
-            .update(input)
-            .finalize(last_chunk_is_root);
+        let hash = crate::hash_chunk(chunk_index, input, last_chunk_finalization, None);
         state.push_subtree(&hash, input.len());
         loop {
             match state.merge_finalize() {
@@ -1328,8 +2768,8 @@ mod test {
         for &case in crate::test::TEST_CASES {
             dbg!(case);
             let input = &buf[..case];
-            let expected = blake3::hash(&input);
-            let found = drive_state(&input);
+            let expected = blake3::hash(input);
+            let found = drive_state(input);
             assert_eq!(expected, found, "hashes don't match");
         }
     }
@@ -1371,14 +2811,163 @@ mod test {
         assert_eq!(r4.unwrap().into_inner(), v);
     }
 
+    fn clear_output(output: &mut io::Cursor<Vec<u8>>) -> io::Result<()> {
+        output.get_mut().clear();
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort_runs_hook() {
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
+        encoder.set_abort_hook(clear_output);
+        encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+        encoder.abort().unwrap();
+        assert!(encoder.into_inner().into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_drop_runs_hook_unless_finalized() {
+        let mut output = Vec::new();
+        {
+            let mut encoder = Encoder::new(io::Cursor::new(&mut output));
+            encoder.set_abort_hook(|cursor| {
+                cursor.get_mut().clear();
+                Ok(())
+            });
+            encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+        }
+        assert!(output.is_empty());
+
+        {
+            let mut encoder = Encoder::new(io::Cursor::new(&mut output));
+            encoder.set_abort_hook(|cursor| {
+                cursor.get_mut().clear();
+                Ok(())
+            });
+            encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+            encoder.finalize().unwrap();
+        }
+        assert_eq!(output, encode([0; 3 * CHUNK_SIZE]).0);
+    }
+
+    #[test]
+    fn test_tee() {
+        let key = [42; KEY_SIZE];
+        for &case in crate::test::TEST_CASES {
+            println!("case {}", case);
+            let input = make_test_input(case);
+            let mut encoder =
+                Encoder::new_tee(io::Cursor::new(Vec::new()), io::Cursor::new(Vec::new()));
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            let (combined, tee) = encoder.into_inner_and_outboard();
+            assert_eq!(encode(&input), (combined.into_inner(), hash));
+            assert_eq!(outboard(&input).0, tee.unwrap().into_inner());
+
+            let mut encoder = Encoder::new_tee_keyed(
+                io::Cursor::new(Vec::new()),
+                io::Cursor::new(Vec::new()),
+                &key,
+            );
+            encoder.write_all(&input).unwrap();
+            let hash = encoder.finalize().unwrap();
+            let (combined, tee) = encoder.into_inner_and_outboard();
+            assert_eq!(encode_keyed(&input, &key), (combined.into_inner(), hash));
+            assert_eq!(outboard_keyed(&input, &key).0, tee.unwrap().into_inner());
+        }
+
+        // The abort hook cleans up both outputs.
+        let mut encoder =
+            Encoder::new_tee(io::Cursor::new(Vec::new()), io::Cursor::new(Vec::new()));
+        encoder.set_abort_hook(clear_output);
+        encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+        encoder.abort().unwrap();
+        let (combined, tee) = encoder.into_inner_and_outboard();
+        assert!(combined.into_inner().is_empty());
+        assert!(tee.unwrap().into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_tee_flush() {
+        // A writer that holds everything until it's flushed, like a BufWriter.
+        #[derive(Default)]
+        struct Buffered {
+            pending: Vec<u8>,
+            flushed: Vec<u8>,
+        }
+
+        impl Write for Buffered {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.flushed.append(&mut self.pending);
+                Ok(())
+            }
+        }
+
+        impl Read for Buffered {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                unreachable!()
+            }
+        }
+
+        impl Seek for Buffered {
+            fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+                unreachable!()
+            }
+        }
+
+        let mut encoder = Encoder::new_tee(Buffered::default(), Buffered::default());
+        encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+        encoder.flush().unwrap();
+        let (combined, tee) = encoder.into_inner_and_outboard();
+        let tee = tee.unwrap();
+        assert!(combined.pending.is_empty());
+        assert!(combined.flushed.len() > 2 * CHUNK_SIZE);
+        assert!(tee.pending.is_empty());
+        assert!(!tee.flushed.is_empty());
+    }
+
+    #[test]
+    fn test_abort_hook_captures_state() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+        let encoder = {
+            let mut encoder =
+                Encoder::new_tee(io::Cursor::new(Vec::new()), io::Cursor::new(Vec::new()));
+            encoder.set_abort_hook(move |_| {
+                hook_calls.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+            encoder.write_all(&[0; 3 * CHUNK_SIZE]).unwrap();
+            encoder
+        };
+        drop(encoder);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_write_after_abort_panics() {
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::<u8>::new()));
+        encoder.abort().unwrap();
+        let _ = encoder.write(&[]);
+    }
+
     #[test]
     fn test_empty_write_after_one_chunk() {
         let input = &[0; CHUNK_SIZE];
-        let mut output = Vec::new();
-        let mut encoder = Encoder::new(io::Cursor::new(&mut output));
+        let mut encoder = Encoder::new(io::Cursor::new(Vec::new()));
         encoder.write_all(input).unwrap();
-        encoder.write(&[]).unwrap();
+        assert_eq!(0, encoder.write(&[]).unwrap());
         let hash = encoder.finalize().unwrap();
+        let output = encoder.into_inner().into_inner();
         assert_eq!((output, hash), encode(input));
         assert_eq!(hash, blake3::hash(input));
     }
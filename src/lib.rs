@@ -43,12 +43,33 @@
 pub mod decode;
 pub mod encode;
 
+/// The root hash of an encoding, re-exported from the `blake3` crate.
+///
+/// `Hash` implements `std::hash::Hash` and constant-time `Eq`, so it can be used directly as a
+/// `HashMap` or `HashSet` key. It doesn't implement `Ord`. To sort hashes, compare their bytes,
+/// and to use them as `BTreeMap` keys, key the map by the `[u8; 32]` bytes instead:
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// let mut hashes = vec![bao::encode::encode(b"foo").1, bao::encode::encode(b"bar").1];
+/// hashes.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+///
+/// let mut map = BTreeMap::new();
+/// for hash in &hashes {
+///     map.insert(*hash.as_bytes(), *hash);
+/// }
+/// assert_eq!(&hashes[0], map.values().next().unwrap());
+/// ```
 pub use blake3::Hash;
 
+use blake3::hazmat::{merge_subtrees_non_root, merge_subtrees_root, HasherExt, Mode};
 use std::mem;
 
 /// The size of a `Hash`, 32 bytes.
 pub const HASH_SIZE: usize = 32;
+/// The size of a key for the keyed mode, 32 bytes.
+pub const KEY_SIZE: usize = 32;
 pub(crate) const PARENT_SIZE: usize = 2 * HASH_SIZE;
 pub(crate) const HEADER_SIZE: usize = 8;
 pub(crate) const CHUNK_SIZE: usize = 1024;
@@ -85,6 +106,55 @@ impl Finalization {
     }
 }
 
+// Chunk and parent hashing go through the blake3::hazmat module, which exposes the interior
+// "chaining values" of the tree. A chunk hasher has to know its starting offset in the whole
+// input, because BLAKE3 mixes the chunk counter into every chunk. In the keyed mode, the key goes
+// into every chunk and parent node, so that only holders of the key can compute (or verify) any
+// of the hashes in the tree.
+pub(crate) fn chunk_hasher(chunk_index: u64, key: Option<&[u8; KEY_SIZE]>) -> blake3::Hasher {
+    let mut hasher = match key {
+        Some(key) => blake3::Hasher::new_keyed(key),
+        None => blake3::Hasher::new(),
+    };
+    hasher.set_input_offset(chunk_index * CHUNK_SIZE as u64);
+    hasher
+}
+
+pub(crate) fn finalize_chunk(hasher: &blake3::Hasher, finalization: Finalization) -> Hash {
+    if finalization.is_root() {
+        hasher.finalize()
+    } else {
+        hasher.finalize_non_root().into()
+    }
+}
+
+pub(crate) fn hash_chunk(
+    chunk_index: u64,
+    chunk: &[u8],
+    finalization: Finalization,
+    key: Option<&[u8; KEY_SIZE]>,
+) -> Hash {
+    finalize_chunk(chunk_hasher(chunk_index, key).update(chunk), finalization)
+}
+
+pub(crate) fn parent_cv(
+    left_child: &Hash,
+    right_child: &Hash,
+    finalization: Finalization,
+    key: Option<&[u8; KEY_SIZE]>,
+) -> Hash {
+    let (left, right) = (left_child.as_bytes(), right_child.as_bytes());
+    let mode = match key {
+        Some(key) => Mode::KeyedHash(key),
+        None => Mode::Hash,
+    };
+    if finalization.is_root() {
+        merge_subtrees_root(left, right, mode)
+    } else {
+        merge_subtrees_non_root(left, right, mode).into()
+    }
+}
+
 #[doc(hidden)]
 pub mod benchmarks {
     pub const CHUNK_SIZE: usize = super::CHUNK_SIZE;
@@ -118,4 +188,22 @@ pub(crate) mod test {
         16 * CHUNK_SIZE,
         16 * CHUNK_SIZE + 1,
     ];
+
+    // Downstream async code needs to move encoders and decoders across threads. These assertions
+    // fail to compile if a change to one of the types loses an auto trait.
+    #[test]
+    fn test_auto_traits() {
+        use std::io::Cursor;
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        type Buf = Cursor<Vec<u8>>;
+        assert_send_sync::<Hash>();
+        assert_send_sync::<decode::Error>();
+        assert_send_sync::<encode::Encoder<Buf>>();
+        assert_send_sync::<encode::SliceExtractor<Buf, Buf>>();
+        assert_send_sync::<decode::Decoder<Buf, Buf>>();
+        assert_send_sync::<decode::SliceDecoder<Buf>>();
+        assert_send_sync::<decode::SampleReport>();
+        assert_send_sync::<decode::Scrubber<Buf, Buf>>();
+        assert_send_sync::<decode::PauseHandle>();
+    }
 }